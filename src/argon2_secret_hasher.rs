@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use axum::async_trait;
+
+use crate::SecretHasher;
+
+/// Secrets are high-entropy random values, so rather than a per-key salt the
+/// pepper is passed to Argon2id as its secret input. This keeps digests
+/// deterministic, and therefore indexable, as [`SecretHasher`] requires.
+const SALT: &[u8] = b"api-key-server";
+
+/// Hashes secrets with Argon2id, keyed by a server-side pepper.
+///
+/// Every lookup and verification of a well-formed secret costs a full
+/// Argon2id hash on the blocking thread pool, whether or not the secret
+/// exists, so callers presenting many secrets can exhaust the server. This
+/// hasher suits low-QPS deployments; others should use
+/// [`Sha256SecretHasher`](crate::sha256_secret_hasher::Sha256SecretHasher)
+/// or lower the cost with [`Self::with_params`].
+#[derive(Clone)]
+pub struct Argon2idSecretHasher {
+    pepper: Vec<u8>,
+    params: Params,
+}
+
+impl Argon2idSecretHasher {
+    /// Hashes with the default Argon2id parameters, which take 19 MiB and
+    /// two passes per hash.
+    pub fn new(pepper: impl Into<Vec<u8>>) -> Result<Arc<Self>, argon2::Error> {
+        Self::with_params(pepper, Params::default())
+    }
+
+    /// Hashes with `params`, which are rejected if Argon2id cannot use them.
+    pub fn with_params(
+        pepper: impl Into<Vec<u8>>,
+        params: Params,
+    ) -> Result<Arc<Self>, argon2::Error> {
+        let hasher = Self {
+            pepper: pepper.into(),
+            params,
+        };
+        hasher.digest("")?;
+        Ok(Arc::new(hasher))
+    }
+
+    fn digest(&self, secret: &str) -> Result<String, argon2::Error> {
+        let argon2 = Argon2::new_with_secret(
+            &self.pepper,
+            Algorithm::Argon2id,
+            Version::V0x13,
+            self.params.clone(),
+        )?;
+        let mut output = vec![
+            0u8;
+            self.params
+                .output_len()
+                .unwrap_or(Params::DEFAULT_OUTPUT_LEN)
+        ];
+        argon2.hash_password_into(secret.as_bytes(), SALT, &mut output)?;
+        Ok(output.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+#[async_trait]
+impl SecretHasher for Argon2idSecretHasher {
+    async fn hash(&self, secret: &str) -> String {
+        let hasher = self.clone();
+        let secret = secret.to_string();
+        tokio::task::spawn_blocking(move || hasher.digest(&secret))
+            .await
+            .expect("Argon2id hashing task panicked")
+            .expect("Argon2id parameters are validated on construction")
+    }
+}
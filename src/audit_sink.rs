@@ -0,0 +1,155 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{AuditEvent, AuditSink, StorageError};
+
+fn internal_error(e: impl ToString) -> StorageError {
+    StorageError::InternalError(e.to_string())
+}
+
+/// Appends events to a file, one JSON object per line, through a handle that
+/// stays open.
+///
+/// Events are synced to disk at most once per sync interval and when the
+/// server shuts down, so a crash of the host, though not of the process, may
+/// lose the events of the last interval. Reading events back scans the file
+/// without blocking writers.
+pub struct JsonLinesAuditSink {
+    path: PathBuf,
+    sync_interval: Duration,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
+    file: Option<File>,
+    synced_at: Instant,
+    unsynced: bool,
+}
+
+impl JsonLinesAuditSink {
+    /// Creates a sink that syncs events to disk at most once a second.
+    pub fn new(path: impl AsRef<Path>) -> Arc<Self> {
+        Self::with_sync_interval(path, Duration::from_secs(1))
+    }
+
+    pub fn with_sync_interval(path: impl AsRef<Path>, sync_interval: Duration) -> Arc<Self> {
+        Arc::new(JsonLinesAuditSink {
+            path: path.as_ref().to_path_buf(),
+            sync_interval,
+            writer: Mutex::new(Writer {
+                file: None,
+                synced_at: Instant::now(),
+                unsynced: false,
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditSink for JsonLinesAuditSink {
+    async fn record(&self, event: &AuditEvent) -> Result<(), StorageError> {
+        let mut line = serde_json::to_vec(event).map_err(internal_error)?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().await;
+        if writer.file.is_none() {
+            writer.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .await
+                    .map_err(internal_error)?,
+            );
+        }
+        let file = writer.file.as_mut().expect("audit log was opened");
+        file.write_all(&line).await.map_err(internal_error)?;
+        // Hands the line to the OS, so that readers see it.
+        file.flush().await.map_err(internal_error)?;
+        writer.unsynced = true;
+
+        if writer.synced_at.elapsed() >= self.sync_interval {
+            sync(&mut writer).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_events(
+        &self,
+        user: &str,
+        limit: usize,
+    ) -> Result<Option<Vec<AuditEvent>>, StorageError> {
+        if limit == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        let file = match File::open(&self.path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(Vec::new())),
+            Err(e) => return Err(internal_error(e)),
+        };
+
+        let mut events = VecDeque::with_capacity(limit);
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await.map_err(internal_error)? {
+            if line.is_empty() {
+                continue;
+            }
+            let event: AuditEvent = match serde_json::from_str(&line) {
+                Ok(event) => event,
+                // The last line may still be being written.
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(internal_error(e)),
+            };
+            if event.actor == user || event.target_user.as_deref() == Some(user) {
+                if events.len() == limit {
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+        }
+        Ok(Some(events.into()))
+    }
+
+    async fn flush(&self) -> Result<(), StorageError> {
+        sync(&mut *self.writer.lock().await).await
+    }
+}
+
+async fn sync(writer: &mut Writer) -> Result<(), StorageError> {
+    if let (Some(file), true) = (&writer.file, writer.unsynced) {
+        file.sync_data().await.map_err(internal_error)?;
+    }
+    writer.synced_at = Instant::now();
+    writer.unsynced = false;
+    Ok(())
+}
+
+/// Writes events to standard output, one JSON object per line, for a log
+/// collector to pick up. Events cannot be read back.
+pub struct StdoutAuditSink;
+
+impl StdoutAuditSink {
+    pub fn new() -> Arc<Self> {
+        Arc::new(StdoutAuditSink)
+    }
+}
+
+#[async_trait]
+impl AuditSink for StdoutAuditSink {
+    async fn record(&self, event: &AuditEvent) -> Result<(), StorageError> {
+        let line = serde_json::to_string(event).map_err(internal_error)?;
+        writeln!(std::io::stdout().lock(), "{}", line).map_err(internal_error)
+    }
+}
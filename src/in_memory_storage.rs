@@ -0,0 +1,225 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    calendar_month, ApiKey, DailyUsage, KeyUse, Owner, RateLimit, RateLimitStatus, StorageAdapter,
+    StorageError, TokenBucket,
+};
+
+pub struct InMemoryStorage {
+    keys: Arc<Mutex<HashMap<Owner, Vec<ApiKey>>>>,
+    rate_limit_buckets: Mutex<HashMap<Uuid, TokenBucket>>,
+    usage: Mutex<HashMap<Uuid, BTreeMap<NaiveDate, u64>>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Arc<Self> {
+        Arc::new(InMemoryStorage {
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            rate_limit_buckets: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for InMemoryStorage {
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let mut keys = self.keys.lock().await;
+        keys.entry(owner.clone()).or_insert_with(Vec::new).push(key);
+        Ok(())
+    }
+
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        let keys = self.keys.lock().await;
+        let now = Utc::now();
+        Ok(keys
+            .get(owner)
+            .into_iter()
+            .flatten()
+            .filter(|key| !key.is_expired(now))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError> {
+        let mut keys = self.keys.lock().await;
+        if let Some(user_keys) = keys.get_mut(owner) {
+            if let Some(index) = user_keys.iter().position(|key| key.id == key_id) {
+                user_keys.remove(index);
+                self.rate_limit_buckets.lock().await.remove(&key_id);
+                self.usage.lock().await.remove(&key_id);
+                Ok(())
+            } else {
+                Err(StorageError::NotFound)
+            }
+        } else {
+            Err(StorageError::NotFound)
+        }
+    }
+
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let mut keys = self.keys.lock().await;
+        if let Some(user_keys) = keys.get_mut(owner) {
+            if let Some(existing_key) = user_keys.iter_mut().find(|k| k.id == key.id) {
+                *existing_key = key;
+                Ok(())
+            } else {
+                Err(StorageError::NotFound)
+            }
+        } else {
+            Err(StorageError::NotFound)
+        }
+    }
+
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        secret: &str,
+    ) -> Result<Option<ApiKey>, StorageError> {
+        let keys = self.keys.lock().await;
+        let now = Utc::now();
+        Ok(keys
+            .get(owner)
+            .and_then(|user_keys| {
+                user_keys
+                    .iter()
+                    .find(|key| key.secret == secret && !key.is_expired(now))
+            })
+            .cloned())
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        let keys = self.keys.lock().await;
+        let now = Utc::now();
+        Ok(keys.iter().find_map(|(owner, user_keys)| {
+            user_keys
+                .iter()
+                .find(|key| key.secret == secret && !key.is_expired(now))
+                .map(|key| (owner.clone(), key.clone()))
+        }))
+    }
+
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError> {
+        let mut keys = self.keys.lock().await;
+        let mut rate_limit_buckets = self.rate_limit_buckets.lock().await;
+        let mut usage = self.usage.lock().await;
+        let mut purged = 0;
+        for user_keys in keys.values_mut() {
+            user_keys.retain(|key| {
+                if !key.is_expired(now) {
+                    return true;
+                }
+                rate_limit_buckets.remove(&key.id);
+                usage.remove(&key.id);
+                purged += 1;
+                false
+            });
+        }
+        keys.retain(|_, user_keys| !user_keys.is_empty());
+        Ok(purged)
+    }
+
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError> {
+        let mut keys = self.keys.lock().await;
+        for key_use in key_uses {
+            let Some(key) = keys
+                .get_mut(&key_use.owner)
+                .and_then(|user_keys| user_keys.iter_mut().find(|k| k.id == key_use.key_id))
+            else {
+                continue;
+            };
+            if key
+                .last_used_at
+                .is_some_and(|last_used_at| last_used_at >= key_use.used_at)
+            {
+                continue;
+            }
+            key.last_used_at = Some(key_use.used_at);
+            key.last_used_ip = key_use.client_ip.or(key.last_used_ip);
+        }
+        Ok(())
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        let keys = self.keys.lock().await;
+        Ok(keys.values().map(|user_keys| user_keys.len() as u64).sum())
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        let mut buckets = self.rate_limit_buckets.lock().await;
+        let (bucket, status) = TokenBucket::take(buckets.get(&key_id).copied(), rate_limit, now);
+        buckets.insert(key_id, bucket);
+        Ok(status)
+    }
+
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        let mut usage = self.usage.lock().await;
+        let days = usage.entry(key_id).or_default();
+        let (from, until) = calendar_month(now.date_naive());
+        let used = days.range(from..until).map(|(_, requests)| requests).sum();
+        if monthly_quota.is_some_and(|quota| used >= quota) {
+            return Ok((false, used));
+        }
+        *days.entry(now.date_naive()).or_default() += 1;
+        Ok((true, used + 1))
+    }
+
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut usage = self.usage.lock().await;
+        let Some(days) = usage.get_mut(&key_id) else {
+            return Ok(());
+        };
+        if let Some(requests) = days.get_mut(&now.date_naive()) {
+            *requests -= 1;
+            if *requests == 0 {
+                days.remove(&now.date_naive());
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        let usage = self.usage.lock().await;
+        Ok(usage
+            .get(&key_id)
+            .map(|days| {
+                days.range(from..until)
+                    .map(|(date, requests)| DailyUsage {
+                        date: *date,
+                        requests: *requests,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
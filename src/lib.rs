@@ -1,27 +1,128 @@
-use std::sync::Arc;
+pub mod argon2_secret_hasher;
+pub mod audit_sink;
+pub mod in_memory_storage;
+mod metrics;
+pub mod organization_directory;
+pub mod owner_resolver;
+#[cfg(feature = "postgres")]
+pub mod postgres_storage;
+pub mod prefixed_secret_generator;
+pub mod rate_limiter;
+#[cfg(feature = "redis")]
+pub mod redis_storage;
+pub mod service_authenticator;
+pub mod sha256_secret_hasher;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_storage;
+/// Export of spans to an OpenTelemetry collector.
+pub mod telemetry;
+pub mod tls;
+/// Spans of requests, which continue the W3C trace context of their callers
+/// and pass it on to webhook receivers.
+mod trace_context;
+pub mod uuid_secret_generator;
+pub mod webhooks;
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use axum::{
     async_trait,
-    extract::{Path, State},
-    http::StatusCode,
-    middleware,
-    response::IntoResponse,
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
-    Json, Router,
+    Extension, Json, Router,
+};
+use axum_auth_provider::{AuthProvider, Claims};
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use subtle::ConstantTimeEq;
+use tokio::{net::TcpListener, sync::Mutex};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use utoipa::{
+    openapi::{
+        content::ContentBuilder,
+        path::{Parameter, ParameterBuilder, ParameterIn},
+        response::ResponseBuilder,
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        Ref, Required,
+    },
+    Modify, OpenApi, PartialSchema,
 };
-use axum_auth_provider::{auth_middleware, AuthProvider, Token};
+use utoipa_swagger_ui::{Config, SwaggerUi};
 use uuid::Uuid;
 
+use crate::{
+    metrics::{InstrumentedStorage, Metrics},
+    owner_resolver::ClaimOwnerResolver,
+    rate_limiter::{InMemoryRateLimiter, StorageRateLimiter},
+    sha256_secret_hasher::Sha256SecretHasher,
+    tls::TlsConfig,
+    webhooks::{WebhookDispatcher, WebhookEvent, WebhookEventType},
+};
+
 pub struct ApiKeyServer {
     auth_provider: Arc<dyn AuthProvider>,
     storage_adapter: Arc<dyn StorageAdapter>,
     secret_generator: Arc<dyn SecretGenerator>,
+    previous_secret_generators: Vec<Arc<dyn SecretGenerator>>,
+    secret_hasher: Arc<dyn SecretHasher>,
+    service_authenticator: Option<Arc<dyn ServiceAuthenticator>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    webhook_dispatcher: Option<Arc<WebhookDispatcher>>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    organization_directory: Option<Arc<dyn OrganizationDirectory>>,
+    owner_resolver: Arc<dyn OwnerResolver>,
+    admin_role: Option<AdminRole>,
+    swagger_ui: bool,
+    expired_key_sweep_interval: Duration,
+    allowed_scopes: HashSet<String>,
+    last_used_recorder: Arc<LastUsedRecorder>,
+    last_used_flush_interval: Duration,
+    metrics: Arc<Metrics>,
+    cors: Option<CorsLayer>,
+    tls: Option<Arc<TlsConfig>>,
+    tls_reload_interval: Duration,
+    drain_timeout: Duration,
+    draining: Arc<AtomicBool>,
 }
 
 pub struct ApiKeyServerBuilder {
     auth_provider: Option<Arc<dyn AuthProvider>>,
     storage_adapter: Option<Arc<dyn StorageAdapter>>,
     secret_generator: Option<Arc<dyn SecretGenerator>>,
+    previous_secret_generators: Vec<Arc<dyn SecretGenerator>>,
+    secret_hasher: Option<Arc<dyn SecretHasher>>,
+    secret_pepper: Option<Vec<u8>>,
+    service_authenticator: Option<Arc<dyn ServiceAuthenticator>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    webhook_dispatcher: Option<Arc<WebhookDispatcher>>,
+    rate_limiter: Option<Arc<dyn RateLimiter>>,
+    storage_rate_limiter: bool,
+    organization_directory: Option<Arc<dyn OrganizationDirectory>>,
+    owner_resolver: Option<Arc<dyn OwnerResolver>>,
+    admin_role: Option<AdminRole>,
+    swagger_ui: bool,
+    expired_key_sweep_interval: Option<Duration>,
+    allowed_scopes: HashSet<String>,
+    last_used_flush_interval: Option<Duration>,
+    cors_allowed_origins: Vec<String>,
+    tls: Option<Arc<TlsConfig>>,
+    tls_reload_interval: Option<Duration>,
+    drain_timeout: Option<Duration>,
 }
 
 impl ApiKeyServer {
@@ -30,27 +131,277 @@ impl ApiKeyServer {
             auth_provider: None,
             storage_adapter: None,
             secret_generator: None,
+            previous_secret_generators: Vec::new(),
+            secret_hasher: None,
+            secret_pepper: None,
+            service_authenticator: None,
+            audit_sink: None,
+            webhook_dispatcher: None,
+            rate_limiter: None,
+            storage_rate_limiter: false,
+            organization_directory: None,
+            owner_resolver: None,
+            admin_role: None,
+            swagger_ui: false,
+            expired_key_sweep_interval: None,
+            allowed_scopes: HashSet::new(),
+            last_used_flush_interval: None,
+            cors_allowed_origins: Vec::new(),
+            tls: None,
+            tls_reload_interval: None,
+            drain_timeout: None,
+        }
+    }
+
+    /// Serves the API on `listener`, over HTTPS if TLS is configured. Webhook
+    /// deliveries left pending when the server last stopped are restarted
+    /// first. For as long as the server runs, expired keys are purged from
+    /// storage, buffered key uses are written to it and certificates are
+    /// reloaded in the background.
+    pub async fn run(self, listener: TcpListener) -> std::io::Result<()> {
+        self.run_until(listener, std::future::pending()).await
+    }
+
+    /// Serves the API like [`ApiKeyServer::run`] until `shutdown` completes.
+    /// The server then fails `/healthz`, stops accepting connections and waits
+    /// for requests in flight to complete, closing the connections that remain
+    /// after the drain timeout. Webhook deliveries get as long again to
+    /// succeed before they are dead-lettered, buffered writes are flushed and
+    /// storage is shut down before returning.
+    pub async fn run_until(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        let storage_adapter = self.storage_adapter.clone();
+        let last_used_recorder = self.last_used_recorder.clone();
+        let audit_sink = self.audit_sink.clone();
+        let webhook_dispatcher = self.webhook_dispatcher.clone();
+        let drain_timeout = self.drain_timeout;
+
+        if let Some(webhook_dispatcher) = &webhook_dispatcher {
+            match webhook_dispatcher.resume().await {
+                Ok(0) => {}
+                Ok(resumed) => tracing::info!("Resumed {} webhook deliveries", resumed),
+                Err(e) => tracing::error!("Failed to resume webhook deliveries: {:?}", e),
+            }
+        }
+
+        let sweeper = tokio::spawn(sweep_expired_keys(
+            storage_adapter.clone(),
+            self.expired_key_sweep_interval,
+        ));
+        let flusher = tokio::spawn(flush_last_used(
+            last_used_recorder.clone(),
+            storage_adapter.clone(),
+            self.last_used_flush_interval,
+        ));
+
+        let reloader = self
+            .tls
+            .clone()
+            .map(|tls| tokio::spawn(reload_tls(tls, self.tls_reload_interval)));
+
+        let handle = axum_server::Handle::new();
+        let drainer = tokio::spawn(drain(
+            shutdown,
+            handle.clone(),
+            self.draining.clone(),
+            self.drain_timeout,
+        ));
+
+        let listener = listener.into_std()?;
+        let result = match self.tls.clone() {
+            Some(tls) => {
+                axum_server::from_tcp_rustls(listener, tls.rustls_config())
+                    .handle(handle)
+                    .serve(self.router().into_make_service())
+                    .await
+            }
+            None => {
+                axum_server::from_tcp(listener)
+                    .handle(handle)
+                    .serve(self.router().into_make_service())
+                    .await
+            }
+        };
+
+        drainer.abort();
+        sweeper.abort();
+        flusher.abort();
+        if let Some(reloader) = reloader {
+            reloader.abort();
+        }
+        if let Err(e) = last_used_recorder.flush(storage_adapter.as_ref()).await {
+            tracing::error!("Failed to record last used keys: {:?}", e);
+        }
+        if let Some(webhook_dispatcher) = webhook_dispatcher {
+            webhook_dispatcher.shutdown(drain_timeout).await;
+        }
+        if let Some(audit_sink) = audit_sink {
+            if let Err(e) = audit_sink.flush().await {
+                tracing::error!("Failed to flush audit events: {:?}", e);
+            }
+        }
+        if let Err(e) = storage_adapter.shutdown().await {
+            tracing::error!("Failed to shut storage down: {:?}", e);
         }
+
+        result
+    }
+
+    /// Describes the routes served by [`ApiKeyServer::router`] as an OpenAPI
+    /// document, which only includes the optional routes that are configured.
+    pub fn openapi(&self) -> utoipa::openapi::OpenApi {
+        let mut openapi = ApiDoc::openapi();
+        openapi.merge(KeyRoutesDoc::openapi());
+        if self.organization_directory.is_some() {
+            openapi.merge(nest_routes(
+                KeyRoutesDoc::openapi(),
+                "/organizations/{organization_id}",
+                path_parameter(
+                    "organization_id",
+                    "Id of the organization that owns the keys",
+                ),
+                "The user is not an admin of the organization",
+                "organization",
+            ));
+        }
+        if self.admin_role.is_some() {
+            openapi.merge(nest_routes(
+                AdminKeyRoutesDoc::openapi(),
+                "/admin/users/{user_id}",
+                path_parameter("user_id", "Id of the user who owns the keys"),
+                "The token was not granted the admin role",
+                "admin",
+            ));
+            openapi.merge(AdminDoc::openapi());
+        }
+        if self.audit_sink.is_some() {
+            openapi.merge(AuditDoc::openapi());
+        }
+        if self.service_authenticator.is_some() {
+            openapi.merge(VerifyDoc::openapi());
+        }
+        openapi
     }
 
     pub fn router(self) -> Router {
+        let openapi = Arc::new(self.openapi());
+        let probes = Probes {
+            storage_adapter: self.storage_adapter.clone(),
+            auth_provider: self.auth_provider.clone(),
+            draining: self.draining.clone(),
+        };
         let app_state = AppState {
             storage_adapter: self.storage_adapter,
             secret_generator: self.secret_generator,
+            previous_secret_generators: Arc::new(self.previous_secret_generators),
+            secret_hasher: self.secret_hasher,
+            allowed_scopes: Arc::new(self.allowed_scopes),
+            last_used_recorder: self.last_used_recorder,
+            metrics: self.metrics.clone(),
+            webhook_dispatcher: self.webhook_dispatcher,
+            rate_limiter: self.rate_limiter,
+            organization_directory: self.organization_directory,
+            owner_resolver: self.owner_resolver,
         };
 
-        Router::new()
+        let key_routes = Router::new()
             .route("/keys", post(create_key))
             .route("/keys", get(list_keys))
             .route("/keys/:id", delete(delete_key))
             .route("/keys/:id", post(regenerate_key))
-            .route("/lookup", post(lookup_key))
-            .with_state(app_state)
+            .route("/keys/:id/usage", get(get_key_usage));
+
+        let mut user_routes = key_routes.clone().route("/lookup", post(lookup_key));
+
+        if app_state.organization_directory.is_some() {
+            user_routes = user_routes.nest(
+                "/organizations/:organization_id",
+                key_routes.route_layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    organization_owner,
+                )),
+            );
+        }
+
+        if let Some(admin_role) = self.admin_role {
+            user_routes = user_routes.nest(
+                "/admin",
+                Router::new()
+                    .route("/users/:user_id/keys", get(list_keys))
+                    .route("/users/:user_id/keys/:id", delete(delete_key))
+                    .route("/users/:user_id/keys/:id", post(regenerate_key))
+                    .route_layer(middleware::from_fn(admin_target))
+                    .route("/keys/search", post(search_keys))
+                    .route_layer(middleware::from_fn_with_state(admin_role, require_admin)),
+            );
+        }
+
+        if let Some(audit_sink) = self.audit_sink {
+            user_routes = user_routes
+                .route_layer(middleware::from_fn_with_state(
+                    audit_sink.clone(),
+                    audit_requests,
+                ))
+                .route(
+                    "/audit",
+                    get(list_audit_events).with_state(audit_sink.clone()),
+                );
+        }
+
+        let mut router = user_routes
+            .with_state(app_state.clone())
             .layer(middleware::from_fn_with_state(
-                self.auth_provider,
-                auth_middleware,
+                app_state.clone(),
+                user_owner,
             ))
-            .route("/healthz", get(healthz))
+            .layer(middleware::from_fn_with_state(
+                self.auth_provider,
+                authenticate,
+            ));
+
+        if let Some(service_authenticator) = self.service_authenticator {
+            router = router.merge(
+                Router::new()
+                    .route("/verify", post(verify_key))
+                    .with_state(app_state.clone())
+                    .layer(middleware::from_fn_with_state(
+                        service_authenticator,
+                        service_auth_middleware,
+                    )),
+            );
+        }
+
+        if self.swagger_ui {
+            router =
+                router.merge(SwaggerUi::new("/swagger-ui").config(Config::new(["/openapi.json"])));
+        }
+
+        let router = router
+            .route_layer(middleware::from_fn_with_state(self.metrics, track_requests))
+            .route("/healthz", get(healthz).with_state(self.draining))
+            .route("/livez", get(livez))
+            .route("/version", get(version))
+            .route("/metrics", get(render_metrics).with_state(app_state))
+            .route("/openapi.json", get(openapi_json).with_state(openapi))
+            .layer(middleware::from_fn(render_problems))
+            // Added after `render_problems`, as an unready server still
+            // responds with its readiness rather than a problem.
+            .route("/readyz", get(readyz).with_state(probes))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(trace_context::request_span)
+                    .on_response(trace_context::record_response),
+            )
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+        match self.cors {
+            Some(cors) => router.layer(cors),
+            None => router,
+        }
     }
 }
 
@@ -70,607 +421,2603 @@ impl ApiKeyServerBuilder {
         self
     }
 
+    /// Keeps accepting at lookup the secrets that `secret_generators` could
+    /// have issued, e.g. those of keys created before the secret generator was
+    /// changed. Secrets that no generator could have issued are rejected
+    /// without a storage lookup.
+    pub fn with_previous_secret_generators(
+        mut self,
+        secret_generators: impl IntoIterator<Item = Arc<dyn SecretGenerator>>,
+    ) -> Self {
+        self.previous_secret_generators = secret_generators.into_iter().collect();
+        self
+    }
+
+    /// Hashes secrets at rest with a hasher other than the default
+    /// [`Sha256SecretHasher`], which takes precedence over
+    /// [`Self::with_secret_pepper`].
+    pub fn with_secret_hasher(mut self, secret_hasher: Arc<dyn SecretHasher>) -> Self {
+        self.secret_hasher = Some(secret_hasher);
+        self
+    }
+
+    /// Sets the server-side pepper that the default [`Sha256SecretHasher`]
+    /// hashes secrets at rest with. Either this or a hasher must be set.
+    pub fn with_secret_pepper(mut self, secret_pepper: impl Into<Vec<u8>>) -> Self {
+        self.secret_pepper = Some(secret_pepper.into());
+        self
+    }
+
+    pub fn with_service_authenticator(
+        mut self,
+        service_authenticator: Arc<dyn ServiceAuthenticator>,
+    ) -> Self {
+        self.service_authenticator = Some(service_authenticator);
+        self
+    }
+
+    /// Records key lifecycle events to `audit_sink`, and lets users read their
+    /// own events back from `GET /audit`.
+    pub fn with_audit_sink(mut self, audit_sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(audit_sink);
+        self
+    }
+
+    /// Notifies the dispatcher's subscribers when keys are created, regenerated
+    /// or deleted.
+    pub fn with_webhook_dispatcher(mut self, webhook_dispatcher: Arc<WebhookDispatcher>) -> Self {
+        self.webhook_dispatcher = Some(webhook_dispatcher);
+        self
+    }
+
+    /// Sets where the rate limits of keys are tracked. Defaults to an
+    /// [`InMemoryRateLimiter`], which only limits requests seen by this process.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self.storage_rate_limiter = false;
+        self
+    }
+
+    /// Tracks the rate limits of keys with a [`StorageRateLimiter`] over the
+    /// storage adapter, so that its calls are timed and counted along with
+    /// the others.
+    pub fn with_storage_rate_limiter(mut self) -> Self {
+        self.rate_limiter = None;
+        self.storage_rate_limiter = true;
+        self
+    }
+
+    /// Lets users manage the keys of the organizations they belong to under
+    /// `/organizations/:organization_id`, and look them up.
+    pub fn with_organization_directory(
+        mut self,
+        organization_directory: Arc<dyn OrganizationDirectory>,
+    ) -> Self {
+        self.organization_directory = Some(organization_directory);
+        self
+    }
+
+    /// Sets which user a bearer token acts as. Defaults to the user named by
+    /// the token's `sub` claim.
+    pub fn with_owner_resolver(mut self, owner_resolver: Arc<dyn OwnerResolver>) -> Self {
+        self.owner_resolver = Some(owner_resolver);
+        self
+    }
+
+    /// Lets tokens granted `admin_role` list, regenerate and delete the keys of
+    /// any user under `/admin/users/:user_id`, and search all keys by secret.
+    pub fn with_admin_role(mut self, admin_role: AdminRole) -> Self {
+        self.admin_role = Some(admin_role);
+        self
+    }
+
+    /// Serves Swagger UI for the `/openapi.json` document under `/swagger-ui`.
+    pub fn with_swagger_ui(mut self) -> Self {
+        self.swagger_ui = true;
+        self
+    }
+
+    pub fn with_expired_key_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expired_key_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Sets the scope names that keys may be issued with. Keys cannot carry any
+    /// scope unless it has been allowed here.
+    pub fn with_allowed_scopes<S: Into<String>>(
+        mut self,
+        allowed_scopes: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.allowed_scopes = allowed_scopes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets how often key uses seen by the lookup and verification endpoints
+    /// are written to storage.
+    pub fn with_last_used_flush_interval(mut self, interval: Duration) -> Self {
+        self.last_used_flush_interval = Some(interval);
+        self
+    }
+
+    /// Lets browsers call the API from `origins`, or from any origin if one of
+    /// them is `*`. Cross-origin requests are not allowed by default.
+    pub fn with_cors_allowed_origins<S: Into<String>>(
+        mut self,
+        origins: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.cors_allowed_origins = origins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serves the API over HTTPS with `tls` rather than plain HTTP.
+    pub fn with_tls(mut self, tls: Arc<TlsConfig>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets how often the TLS certificate files are checked for changes.
+    pub fn with_tls_reload_interval(mut self, interval: Duration) -> Self {
+        self.tls_reload_interval = Some(interval);
+        self
+    }
+
+    /// Sets how long requests in flight may take to complete once the server
+    /// is shutting down, before their connections are closed.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+
     pub fn build(self) -> Result<ApiKeyServer, Box<dyn std::error::Error>> {
+        let metrics = Metrics::new();
+        let cors = cors_layer(&self.cors_allowed_origins)?;
+        let storage_adapter: Arc<dyn StorageAdapter> = InstrumentedStorage::new(
+            self.storage_adapter
+                .ok_or_else(|| "Storage not provided".to_string())?,
+            metrics.clone(),
+        );
+        let rate_limiter: Arc<dyn RateLimiter> = match self.rate_limiter {
+            Some(rate_limiter) => rate_limiter,
+            None if self.storage_rate_limiter => StorageRateLimiter::new(storage_adapter.clone()),
+            None => InMemoryRateLimiter::new(),
+        };
         Ok(ApiKeyServer {
             auth_provider: self
                 .auth_provider
                 .ok_or_else(|| "Auth provider not provided".to_string())?,
-            storage_adapter: self
-                .storage_adapter
-                .ok_or_else(|| "Storage not provided".to_string())?,
+            storage_adapter,
             secret_generator: self
                 .secret_generator
                 .ok_or_else(|| "Secret generator not provided".to_string())?,
+            previous_secret_generators: self.previous_secret_generators,
+            secret_hasher: match (self.secret_hasher, self.secret_pepper) {
+                (Some(secret_hasher), _) => secret_hasher,
+                (None, Some(secret_pepper)) => Sha256SecretHasher::new(secret_pepper),
+                (None, None) => {
+                    return Err("Secret pepper not provided, set one with \
+                        `with_secret_pepper` or a hasher with `with_secret_hasher`"
+                        .into())
+                }
+            },
+            service_authenticator: self.service_authenticator,
+            audit_sink: self.audit_sink,
+            webhook_dispatcher: self.webhook_dispatcher,
+            rate_limiter,
+            organization_directory: self.organization_directory,
+            owner_resolver: self
+                .owner_resolver
+                .unwrap_or_else(|| ClaimOwnerResolver::new("sub")),
+            admin_role: self.admin_role,
+            swagger_ui: self.swagger_ui,
+            expired_key_sweep_interval: self
+                .expired_key_sweep_interval
+                .unwrap_or(Duration::from_secs(60)),
+            allowed_scopes: self.allowed_scopes,
+            last_used_recorder: Arc::new(LastUsedRecorder::default()),
+            last_used_flush_interval: self
+                .last_used_flush_interval
+                .unwrap_or(Duration::from_secs(10)),
+            metrics,
+            cors,
+            tls: self.tls,
+            tls_reload_interval: self.tls_reload_interval.unwrap_or(Duration::from_secs(10)),
+            drain_timeout: self.drain_timeout.unwrap_or(Duration::from_secs(30)),
+            draining: Arc::new(AtomicBool::new(false)),
         })
     }
 }
 
+/// Allows cross-origin requests from `origins` to the API, exposing the headers
+/// that clients may need to read.
+fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>, String> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| {
+                    header::HeaderValue::from_str(origin)
+                        .map_err(|_| format!("Invalid CORS origin: {}", origin))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::HeaderName::from_static("x-request-id"),
+            ])
+            .expose_headers([
+                header::RETRY_AFTER,
+                header::HeaderName::from_static("x-request-id"),
+                header::HeaderName::from_static("ratelimit-limit"),
+                header::HeaderName::from_static("ratelimit-remaining"),
+                header::HeaderName::from_static("ratelimit-reset"),
+                header::HeaderName::from_static(ADMIN_ACTOR_HEADER),
+            ]),
+    ))
+}
+
+/// Persists API keys on behalf of their owners, which are either users or
+/// organizations. Keys of different owners never mix, even when their ids are
+/// equal.
+///
+/// Lookups and listings must never return a key whose `expires_at` has passed,
+/// even before `purge_expired_keys` has removed it.
 #[async_trait]
 pub trait StorageAdapter: Send + Sync {
-    async fn create_key(&self, user_id: &str, key: ApiKey) -> Result<(), StorageError>;
-    async fn list_keys(&self, user_id: &str) -> Result<Vec<ApiKey>, StorageError>;
-    async fn delete_key(&self, user_id: &str, key_id: Uuid) -> Result<(), StorageError>;
-    async fn update_key(&self, user_id: &str, key: ApiKey) -> Result<(), StorageError>;
-    async fn lookup_key(&self, user_id: &str, secret: &str)
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError>;
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError>;
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError>;
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError>;
+    async fn lookup_key(&self, owner: &Owner, secret: &str)
         -> Result<Option<ApiKey>, StorageError>;
+    /// Looks a key up by secret regardless of its owner, returning the owner
+    /// alongside the key.
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError>;
+    /// Deletes every key that expired at or before `now`, returning how many
+    /// keys were removed.
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError>;
+    /// Records when keys were last used. Uses of keys that no longer exist are
+    /// ignored, and a use never overwrites a more recent one.
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError>;
+    /// Counts the keys of all owners, including expired keys that have not been
+    /// purged yet.
+    async fn count_keys(&self) -> Result<u64, StorageError>;
+    /// Checks that storage can currently serve requests, as cheaply as it can.
+    async fn health_check(&self) -> Result<(), StorageError>;
+    /// Takes a request from the token bucket of `key_id`, atomically with any
+    /// concurrent call. See [`TokenBucket::take`].
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError>;
+    /// Counts a use of `key_id` on the day of `now`, unless the key has already
+    /// used up its `monthly_quota` in that calendar month, atomically with any
+    /// concurrent call. Returns whether the use was counted, and how many uses
+    /// were counted in the month so far.
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError>;
+    /// Takes back a use of `key_id` that [`Self::increment_usage`] counted at
+    /// `now`, for a request that was then rejected. The day's uses never go
+    /// below zero.
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError>;
+    /// Returns the uses of `key_id` on each day from `from` until `until`
+    /// excluded, oldest first. Days without uses are left out.
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError>;
+    /// Releases the resources of this adapter, such as pooled connections, when
+    /// the server shuts down. No other method is called afterwards.
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Whether a key belongs to a single user or is shared by an organization.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    serde::Deserialize,
+    serde::Serialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerKind {
+    #[default]
+    User,
+    Organization,
+}
+
+impl OwnerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OwnerKind::User => "user",
+            OwnerKind::Organization => "organization",
+        }
+    }
+}
+
+impl std::str::FromStr for OwnerKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "user" => Ok(OwnerKind::User),
+            "organization" => Ok(OwnerKind::Organization),
+            _ => Err(format!("Unknown owner type: {}", kind)),
+        }
+    }
+}
+
+/// The user or organization a key belongs to.
+#[derive(
+    Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize, utoipa::ToSchema,
+)]
+pub struct Owner {
+    #[serde(rename = "owner_type", default)]
+    pub kind: OwnerKind,
+    #[serde(rename = "owner_id")]
+    pub id: String,
+}
+
+impl Owner {
+    pub fn user(id: impl Into<String>) -> Self {
+        Owner {
+            kind: OwnerKind::User,
+            id: id.into(),
+        }
+    }
+
+    pub fn organization(id: impl Into<String>) -> Self {
+        Owner {
+            kind: OwnerKind::Organization,
+            id: id.into(),
+        }
+    }
 }
 
+/// Errors of [`StorageAdapter`]s and of the other components that persist
+/// state. Every variant is answered with its own HTTP status.
 #[derive(Debug)]
 pub enum StorageError {
     NotFound,
+    /// The change conflicts with what is already stored, e.g. a duplicate id.
+    Conflict(String),
+    /// The storage cannot be reached for now. Callers may retry, after
+    /// `retry_after` if the storage gave a hint.
+    Unavailable {
+        reason: String,
+        retry_after: Option<Duration>,
+    },
+    /// The storage did not answer in time.
+    Timeout(String),
+    /// The storage rejected a value it was given, e.g. one that is too long.
+    InvalidInput(String),
     InternalError(String),
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+impl StorageError {
+    pub fn unavailable(reason: impl ToString) -> Self {
+        StorageError::Unavailable {
+            reason: reason.to_string(),
+            retry_after: None,
+        }
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "not found"),
+            StorageError::Conflict(reason) => write!(f, "conflict: {}", reason),
+            StorageError::Unavailable { reason, .. } => write!(f, "unavailable: {}", reason),
+            StorageError::Timeout(reason) => write!(f, "timed out: {}", reason),
+            StorageError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            StorageError::InternalError(reason) => write!(f, "internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+#[derive(Default, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 pub struct InputApiKey {
     pub name: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Lifetime of the key in seconds, as an alternative to `expires_at`.
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub rate_limit: Option<RateLimit>,
+    /// Uses allowed per calendar month, in UTC.
+    pub monthly_quota: Option<u64>,
 }
 
-#[derive(Clone, serde::Deserialize, serde::Serialize)]
+/// An API key as stored by a [`StorageAdapter`].
+///
+/// At rest `secret` always holds the [`SecretHasher`] digest of the secret. The
+/// plaintext secret is only ever placed in it when the key is returned to its
+/// owner right after `create_key` or `regenerate_key`.
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 pub struct ApiKey {
     pub id: Uuid,
     pub name: String,
     pub secret: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = "ip")]
+    pub last_used_ip: Option<IpAddr>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
 }
 
-#[derive(Clone, serde::Deserialize, serde::Serialize)]
+impl ApiKey {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 pub struct ProtectedApiKey {
     pub id: Uuid,
     pub name: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub scopes: Vec<String>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Option<String>, format = "ip")]
+    pub last_used_ip: Option<IpAddr>,
+    pub rate_limit: Option<RateLimit>,
+    pub monthly_quota: Option<u64>,
+}
+
+impl From<ApiKey> for ProtectedApiKey {
+    fn from(key: ApiKey) -> Self {
+        ProtectedApiKey {
+            id: key.id,
+            name: key.name,
+            expires_at: key.expires_at,
+            scopes: key.scopes,
+            last_used_at: key.last_used_at,
+            last_used_ip: key.last_used_ip,
+            rate_limit: key.rate_limit,
+            monthly_quota: key.monthly_quota,
+        }
+    }
+}
+
+/// Allows `requests` requests per `period_seconds`, refilled continuously.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period_seconds: u64,
+}
+
+impl RateLimit {
+    fn tokens_per_millisecond(&self) -> f64 {
+        self.requests as f64 / (self.period_seconds as f64 * 1000.0)
+    }
+}
+
+/// The state of a key's rate limit after a request.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// When the limit will be fully replenished.
+    pub reset_at: DateTime<Utc>,
+    /// When the next request will be allowed, if this one was not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl RateLimitStatus {
+    /// Describes a bucket holding `tokens` at `now`, once the request has
+    /// been allowed or not.
+    pub fn new(rate_limit: &RateLimit, tokens: f64, allowed: bool, now: DateTime<Utc>) -> Self {
+        let rate = rate_limit.tokens_per_millisecond();
+        let after = |tokens: f64| {
+            now + chrono::Duration::milliseconds((tokens.max(0.0) / rate).ceil() as i64)
+        };
+        RateLimitStatus {
+            allowed,
+            limit: rate_limit.requests,
+            remaining: tokens.floor() as u32,
+            reset_at: after(rate_limit.requests as f64 - tokens),
+            retry_at: (!allowed).then(|| after(1.0 - tokens)),
+        }
+    }
+}
+
+/// Token bucket state of a key, as kept by a [`RateLimiter`].
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated_at: DateTime<Utc>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+impl TokenBucket {
+    /// Refills `bucket` up to `now` and takes one token from it if there is
+    /// one. A missing bucket is full.
+    pub fn take(
+        bucket: Option<TokenBucket>,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> (TokenBucket, RateLimitStatus) {
+        let capacity = rate_limit.requests as f64;
+        let mut tokens = bucket.map_or(capacity, |bucket| {
+            let elapsed = (now - bucket.updated_at).num_milliseconds().max(0) as f64;
+            (bucket.tokens + elapsed * rate_limit.tokens_per_millisecond()).min(capacity)
+        });
+
+        let allowed = tokens >= 1.0;
+        if allowed {
+            tokens -= 1.0;
+        }
+
+        (
+            TokenBucket {
+                tokens,
+                updated_at: now,
+            },
+            RateLimitStatus::new(rate_limit, tokens, allowed, now),
+        )
+    }
+}
+
+/// The state of a key's monthly quota after a request.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct QuotaStatus {
+    pub allowed: bool,
+    pub quota: u64,
+    pub used: u64,
+    pub remaining: u64,
+    /// When usage is reset, at the start of the next calendar month.
+    pub reset_at: DateTime<Utc>,
+}
+
+impl QuotaStatus {
+    /// Describes a quota of which `used` uses were counted in the month of
+    /// `now`, once the request has been allowed or not.
+    pub fn new(quota: u64, used: u64, allowed: bool, now: DateTime<Utc>) -> Self {
+        let (_, until) = calendar_month(now.date_naive());
+        QuotaStatus {
+            allowed,
+            quota,
+            used,
+            remaining: quota.saturating_sub(used),
+            reset_at: until.and_time(NaiveTime::MIN).and_utc(),
+        }
+    }
+}
+
+/// Uses of a key on a single day, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct DailyUsage {
+    pub date: NaiveDate,
+    pub requests: u64,
+}
+
+/// Usage of a key over a calendar month, as returned by `GET /keys/:id/usage`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct KeyUsage {
+    pub key_id: Uuid,
+    /// First day of the month.
+    pub from: NaiveDate,
+    /// First day of the following month.
+    pub until: NaiveDate,
+    pub monthly_quota: Option<u64>,
+    pub used: u64,
+    pub remaining: Option<u64>,
+    pub daily: Vec<DailyUsage>,
+}
+
+/// Returns the first day of the calendar month of `date` and the first day of
+/// the month after it.
+fn calendar_month(date: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let from = date - chrono::Days::new(date.day0() as u64);
+    let until = from
+        .checked_add_months(Months::new(1))
+        .unwrap_or(NaiveDate::MAX);
+    (from, until)
+}
+
+#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 pub struct LookupSecret {
     pub secret: String,
+    /// Address of the client that presented the secret, recorded as the key's
+    /// `last_used_ip`.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = "ip")]
+    pub client_ip: Option<IpAddr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyUse {
+    pub owner: Owner,
+    pub key_id: Uuid,
+    pub used_at: DateTime<Utc>,
+    pub client_ip: Option<IpAddr>,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct VerifiedApiKey {
+    #[serde(flatten)]
+    pub owner: Owner,
+    #[serde(flatten)]
+    pub key: ProtectedApiKey,
+    /// Set when the key has a rate limit.
+    pub rate_limit_status: Option<RateLimitStatus>,
+    /// Set when the key has a monthly quota.
+    pub quota_status: Option<QuotaStatus>,
+}
+
+/// A key found by `POST /admin/keys/search`, along with its owner.
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct OwnedApiKey {
+    #[serde(flatten)]
+    pub owner: Owner,
+    #[serde(flatten)]
+    pub key: ProtectedApiKey,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct KeySearch {
+    pub secret: String,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct LookedUpApiKey {
+    #[serde(flatten)]
+    pub key: ProtectedApiKey,
+    /// Set when the key has a rate limit.
+    pub rate_limit_status: Option<RateLimitStatus>,
+    /// Set when the key has a monthly quota.
+    pub quota_status: Option<QuotaStatus>,
 }
 
 #[async_trait]
 pub trait SecretGenerator: Send + Sync {
     async fn generate(&self) -> String;
+
+    /// Cheaply checks whether `secret` could have been generated by this
+    /// generator, so malformed secrets are rejected without a storage lookup.
+    fn validate_format(&self, _secret: &str) -> bool {
+        true
+    }
+}
+
+/// Derives the digest that is stored in place of a secret.
+///
+/// Digests must be deterministic, so that storage adapters can index keys by
+/// digest and `lookup_key` stays a single query.
+#[async_trait]
+pub trait SecretHasher: Send + Sync {
+    async fn hash(&self, secret: &str) -> String;
+}
+
+/// Authenticates the trusted backend services allowed to verify any user's keys
+/// through `POST /verify`, independently of the end-user [`AuthProvider`].
+#[async_trait]
+pub trait ServiceAuthenticator: Send + Sync {
+    async fn authenticate(&self, credential: &str) -> bool;
+}
+
+/// Every claim of a verified bearer token, including the ones [`Claims`] leaves
+/// out.
+///
+/// [`Claims`]: axum_auth_provider::Claims
+#[derive(Clone, Debug, Default)]
+pub struct TokenClaims(pub serde_json::Map<String, serde_json::Value>);
+
+impl TokenClaims {
+    pub fn get(&self, name: &str) -> Option<&serde_json::Value> {
+        self.0.get(name)
+    }
+
+    /// Returns the value of the claim `name` if it is a string, or its string
+    /// elements if it is an array.
+    pub fn strings(&self, name: &str) -> Vec<&str> {
+        match self.get(name) {
+            Some(serde_json::Value::String(value)) => vec![value.as_str()],
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().filter_map(|value| value.as_str()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrganizationRole {
+    /// Can look up the organization's keys.
+    Member,
+    /// Can also create, list, regenerate and delete them.
+    Admin,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Membership {
+    pub organization_id: String,
+    pub role: OrganizationRole,
+}
+
+/// Decides which organizations users belong to.
+#[async_trait]
+pub trait OrganizationDirectory: Send + Sync {
+    /// Returns the memberships of the user `user_id`, whose token carries
+    /// `claims`.
+    async fn memberships(
+        &self,
+        user_id: &str,
+        claims: &TokenClaims,
+    ) -> Result<Vec<Membership>, StorageError>;
+}
+
+/// Decides which tokens may act on the keys of every user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminRole {
+    /// Granted to tokens whose claim `name` is `value`, or an array including
+    /// it.
+    Claim { name: String, value: String },
+    /// Granted to tokens whose space-separated `scope` claim, or whose `scp` or
+    /// `permissions` array, includes the scope.
+    Scope(String),
+}
+
+impl AdminRole {
+    pub fn is_granted(&self, claims: &TokenClaims) -> bool {
+        match self {
+            AdminRole::Claim { name, value } => claims.strings(name).contains(&value.as_str()),
+            AdminRole::Scope(scope) => claims
+                .strings("scope")
+                .into_iter()
+                .flat_map(str::split_whitespace)
+                .chain(claims.strings("scp"))
+                .chain(claims.strings("permissions"))
+                .any(|granted| granted == scope),
+        }
+    }
+}
+
+/// Decides which user a verified bearer token acts as, so that tokens whose
+/// meaningful identity is not in `sub` can own keys.
+#[async_trait]
+pub trait OwnerResolver: Send + Sync {
+    /// Returns the id of the user whose keys a token with `claims` manages, or
+    /// `None` if the token does not identify one.
+    async fn resolve(&self, claims: &TokenClaims) -> Option<String>;
+}
+
+/// Tracks how much of their rate limit keys have used.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    async fn acquire(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+    ) -> Result<RateLimitStatus, StorageError>;
+}
+
+/// Receives an [`AuditEvent`] for every key lifecycle request.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, event: &AuditEvent) -> Result<(), StorageError>;
+
+    /// Returns the latest `limit` events of requests that `user` made or that
+    /// admins made on their keys, oldest first, or `None` if this sink cannot
+    /// read events back.
+    async fn list_events(
+        &self,
+        _user: &str,
+        _limit: usize,
+    ) -> Result<Option<Vec<AuditEvent>>, StorageError> {
+        Ok(None)
+    }
+
+    /// Writes out any events that are still buffered. Called when the server
+    /// shuts down.
+    async fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Regenerate,
+    Delete,
+    Lookup,
+    Search,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    NotFound,
+    Invalid,
+    Error,
+}
+
+impl From<StatusCode> for AuditOutcome {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            status if status.is_success() => AuditOutcome::Success,
+            StatusCode::NOT_FOUND => AuditOutcome::NotFound,
+            status if status.is_client_error() => AuditOutcome::Invalid,
+            _ => AuditOutcome::Error,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    /// Id of the user who made the request.
+    pub actor: String,
+    pub action: AuditAction,
+    /// The key acted on, if the request identified one.
+    pub key_id: Option<Uuid>,
+    pub outcome: AuditOutcome,
+    /// Whether the actor made the request through the admin routes.
+    #[serde(default)]
+    pub admin: bool,
+    /// The user whose keys an admin acted on, if the request named one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_user: Option<String>,
+}
+
+/// Marks a response with the key it acted on, for routes where the key id is
+/// not part of the path.
+#[derive(Clone, Copy)]
+struct AuditedKey(Uuid);
+
+/// Compares two strings in time that depends only on their lengths.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Media type of the problem documents that errors are rendered as.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// How long clients are asked to wait before retrying when storage is
+/// unavailable and did not say for how long.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// An error response, rendered as an RFC 7807 problem document.
+///
+/// Error responses that do not come from an `ApiError`, such as rejections of
+/// malformed requests, are rendered the same way, with a `code` derived from
+/// their status.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct ApiError {
+    /// Always `about:blank`, as problems are told apart by their `code`.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The reason phrase of `status`.
+    pub title: String,
+    pub status: u16,
+    /// Stable, machine-readable name of the problem, such as `key_not_found`.
+    pub code: String,
+    /// Human-readable explanation of this occurrence of the problem.
+    pub detail: String,
+    /// Id of the request, as in its `x-request-id` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The state of the key's rate limit, if it was exceeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_status: Option<RateLimitStatus>,
+    /// The state of the key's monthly quota, if it was used up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_status: Option<QuotaStatus>,
+    /// When the client may retry, sent as the `Retry-After` header.
+    #[serde(skip)]
+    retry_after: Option<Duration>,
 }
 
-#[derive(Clone)]
-struct AppState {
-    storage_adapter: Arc<dyn StorageAdapter>,
-    secret_generator: Arc<dyn SecretGenerator>,
+impl ApiError {
+    pub fn new(status: StatusCode, code: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            problem_type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            code: code.into(),
+            detail: detail.into(),
+            request_id: None,
+            rate_limit_status: None,
+            quota_status: None,
+            retry_after: None,
+        }
+    }
+
+    /// A problem for a response that did not come with one, named after its
+    /// `status`, e.g. `method_not_allowed`.
+    fn from_status(status: StatusCode, detail: Option<String>) -> Self {
+        let reason = status.canonical_reason().unwrap_or("Error");
+        Self::new(
+            status,
+            reason.to_lowercase().replace([' ', '-'], "_"),
+            detail.unwrap_or_else(|| reason.to_string()),
+        )
+    }
+
+    fn bad_request(code: &str, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, detail)
+    }
+
+    fn key_not_found(detail: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "key_not_found", detail)
+    }
+
+    /// An internal error whose cause is logged but not shown to clients, as it
+    /// may reveal details of the storage.
+    fn internal(detail: &str, e: impl std::fmt::Debug) -> Self {
+        tracing::error!("{}: {:?}", detail, e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", detail)
+    }
+
+    /// An error for a failed storage call, with a status that tells clients
+    /// whether it is worth retrying. Only the reason of `InvalidInput` errors is
+    /// shown to clients, the others are logged.
+    fn storage(detail: &str, e: StorageError) -> Self {
+        match e {
+            StorageError::NotFound => Self::key_not_found("There is no such key"),
+            StorageError::Conflict(reason) => {
+                tracing::error!("{}: {}", detail, reason);
+                Self::new(
+                    StatusCode::CONFLICT,
+                    "conflict",
+                    format!("{}: it conflicts with the stored state", detail),
+                )
+            }
+            StorageError::Unavailable {
+                reason,
+                retry_after,
+            } => {
+                tracing::error!("{}: {}", detail, reason);
+                Self {
+                    retry_after: Some(retry_after.unwrap_or(DEFAULT_RETRY_AFTER)),
+                    ..Self::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "storage_unavailable",
+                        format!("{}: storage is unavailable", detail),
+                    )
+                }
+            }
+            StorageError::Timeout(reason) => {
+                tracing::error!("{}: {}", detail, reason);
+                Self::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "storage_timeout",
+                    format!("{}: storage timed out", detail),
+                )
+            }
+            StorageError::InvalidInput(reason) => Self::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_input",
+                format!("{}: {}", detail, reason),
+            ),
+            e @ StorageError::InternalError(_) => Self::internal(detail, e),
+        }
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut headers = rate_limit_headers(self.rate_limit_status.as_ref());
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(
+                header::RETRY_AFTER,
+                (retry_after.as_secs_f64().ceil() as u64).max(1).into(),
+            );
+        }
+        let mut response = (
+            self.status_code(),
+            [(header::CONTENT_TYPE, PROBLEM_JSON)],
+            headers,
+            serde_json::to_vec(&self).unwrap_or_default(),
+        )
+            .into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Renders every error response as a problem document carrying the id of the
+/// request. Responses that are not [`ApiError`]s keep their headers, and their
+/// plain text body, if any, becomes the problem's `detail`.
+async fn render_problems(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let mut error = match parts.extensions.remove::<ApiError>() {
+        Some(error) => error,
+        None => {
+            let is_text = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.starts_with("text/plain"));
+            let detail = match is_text {
+                true => axum::body::to_bytes(body, 64 * 1024)
+                    .await
+                    .ok()
+                    .map(|body| String::from_utf8_lossy(&body).into_owned())
+                    .filter(|detail| !detail.is_empty()),
+                false => None,
+            };
+            ApiError::from_status(status, detail)
+        }
+    };
+    error.request_id = request_id;
+
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut response = error.into_response();
+    response.headers_mut().extend(parts.headers);
+    response.extensions_mut().extend(parts.extensions);
+    response
+}
+
+#[derive(Clone)]
+struct AppState {
+    storage_adapter: Arc<dyn StorageAdapter>,
+    secret_generator: Arc<dyn SecretGenerator>,
+    previous_secret_generators: Arc<Vec<Arc<dyn SecretGenerator>>>,
+    secret_hasher: Arc<dyn SecretHasher>,
+    allowed_scopes: Arc<HashSet<String>>,
+    last_used_recorder: Arc<LastUsedRecorder>,
+    metrics: Arc<Metrics>,
+    webhook_dispatcher: Option<Arc<WebhookDispatcher>>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    organization_directory: Option<Arc<dyn OrganizationDirectory>>,
+    owner_resolver: Arc<dyn OwnerResolver>,
+}
+
+impl AppState {
+    /// Whether `secret` could have been issued by the secret generator or any
+    /// previous one.
+    fn is_well_formed(&self, secret: &str) -> bool {
+        self.secret_generator.validate_format(secret)
+            || self
+                .previous_secret_generators
+                .iter()
+                .any(|secret_generator| secret_generator.validate_format(secret))
+    }
+
+    /// Takes a request from the rate limit of `key`, if it has one. Requests
+    /// over the limit are turned into a 429 error.
+    async fn consume_rate_limit(
+        &self,
+        route: &str,
+        key: &ApiKey,
+    ) -> Result<Option<RateLimitStatus>, ApiError> {
+        let Some(rate_limit) = &key.rate_limit else {
+            return Ok(None);
+        };
+
+        match self.rate_limiter.acquire(key.id, rate_limit).await {
+            Ok(status) if status.allowed => Ok(Some(status)),
+            Ok(status) => {
+                self.metrics.record_lookup(route, "rate_limited");
+                Err(ApiError {
+                    rate_limit_status: Some(status),
+                    ..ApiError::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "rate_limited",
+                        "The key is over its rate limit",
+                    )
+                })
+            }
+            Err(e) => Err(ApiError::storage("Failed to check rate limit", e)),
+        }
+    }
+
+    /// Counts a request against the usage of `key`, then takes it from its
+    /// rate limit. Requests over its monthly quota are turned into a 402 error
+    /// without taking from its rate limit, and the use of requests that are
+    /// then rejected, e.g. as over the rate limit, is refunded.
+    async fn take_request(
+        &self,
+        route: &str,
+        key: &ApiKey,
+    ) -> Result<(Option<RateLimitStatus>, Option<QuotaStatus>), ApiError> {
+        let now = Utc::now();
+        let quota_status = self.increment_usage(route, key, now).await?;
+        match self.consume_rate_limit(route, key).await {
+            Ok(rate_limit_status) => Ok((rate_limit_status, quota_status)),
+            Err(error) => {
+                if let Err(e) = self.storage_adapter.refund_usage(key.id, now).await {
+                    tracing::warn!("Failed to refund the use of key {}: {:?}", key.id, e);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Counts a request against the usage of `key`, unless it has used up its
+    /// monthly quota, in which case the request is turned into a 402 error.
+    async fn increment_usage(
+        &self,
+        route: &str,
+        key: &ApiKey,
+        now: DateTime<Utc>,
+    ) -> Result<Option<QuotaStatus>, ApiError> {
+        match self
+            .storage_adapter
+            .increment_usage(key.id, key.monthly_quota, now)
+            .await
+        {
+            Ok((allowed, used)) => match key
+                .monthly_quota
+                .map(|quota| QuotaStatus::new(quota, used, allowed, now))
+            {
+                Some(status) if !status.allowed => Err(self.quota_exceeded(route, status)),
+                status => Ok(status),
+            },
+            Err(e) => Err(ApiError::storage("Failed to record usage", e)),
+        }
+    }
+
+    fn quota_exceeded(&self, route: &str, status: QuotaStatus) -> ApiError {
+        self.metrics.record_lookup(route, "over_quota");
+        ApiError {
+            quota_status: Some(status),
+            ..ApiError::new(
+                StatusCode::PAYMENT_REQUIRED,
+                "quota_exceeded",
+                "The key has used up its monthly quota",
+            )
+        }
+    }
+
+    /// Looks up a key of `owner` by secret digest, or else a key of one of the
+    /// organizations they belong to.
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        claims: &TokenClaims,
+        digest: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        if let Some(key) = self.storage_adapter.lookup_key(owner, digest).await? {
+            return Ok(Some((owner.clone(), key)));
+        }
+        let Some(organization_directory) = &self.organization_directory else {
+            return Ok(None);
+        };
+
+        for membership in organization_directory
+            .memberships(&owner.id, claims)
+            .await?
+        {
+            let organization = Owner::organization(membership.organization_id);
+            if let Some(key) = self
+                .storage_adapter
+                .lookup_key(&organization, digest)
+                .await?
+            {
+                return Ok(Some((organization, key)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn notify(
+        &self,
+        event_type: WebhookEventType,
+        owner: &Owner,
+        key_id: Uuid,
+        key: Option<&ApiKey>,
+    ) {
+        if let Some(webhook_dispatcher) = &self.webhook_dispatcher {
+            webhook_dispatcher
+                .dispatch(WebhookEvent::new(
+                    event_type,
+                    owner.clone(),
+                    key_id,
+                    key.cloned().map(ProtectedApiKey::from),
+                ))
+                .await;
+        }
+    }
+}
+
+/// Buffers key uses in memory, so that lookups do not each write to storage.
+/// Only the latest use of every key is kept until the next flush.
+#[derive(Default)]
+struct LastUsedRecorder {
+    pending: Mutex<HashMap<Uuid, KeyUse>>,
+}
+
+impl LastUsedRecorder {
+    async fn record(&self, key_use: KeyUse) {
+        let mut pending = self.pending.lock().await;
+        match pending.get_mut(&key_use.key_id) {
+            Some(pending_use) if pending_use.used_at > key_use.used_at => {
+                pending_use.client_ip = pending_use.client_ip.or(key_use.client_ip);
+            }
+            Some(pending_use) => {
+                pending_use.used_at = key_use.used_at;
+                pending_use.client_ip = key_use.client_ip.or(pending_use.client_ip);
+            }
+            None => {
+                pending.insert(key_use.key_id, key_use);
+            }
+        }
+    }
+
+    async fn flush(&self, storage_adapter: &dyn StorageAdapter) -> Result<(), StorageError> {
+        let key_uses = std::mem::take(&mut *self.pending.lock().await)
+            .into_values()
+            .collect::<Vec<KeyUse>>();
+        if key_uses.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = storage_adapter.record_last_used(&key_uses).await {
+            for key_use in key_uses {
+                self.record(key_use).await;
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/keys",
+    tag = "keys",
+    request_body = InputApiKey,
+    responses(
+        (status = 200, description = "The created key, including its secret", body = ApiKey),
+        (status = 400, description = "The key is invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 409, description = "The key conflicts with a stored one", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id))]
 async fn create_key(
     State(app_state): State<AppState>,
-    token_data: Token,
+    Extension(owner): Extension<Owner>,
     Json(key): Json<InputApiKey>,
 ) -> impl IntoResponse {
+    let now = Utc::now();
+    let expires_at = match (key.expires_at, key.ttl) {
+        (Some(_), Some(_)) => {
+            return ApiError::bad_request(
+                "invalid_expiration",
+                "Only one of expires_at and ttl can be set",
+            )
+            .into_response()
+        }
+        (Some(expires_at), None) if expires_at <= now => {
+            return ApiError::bad_request("invalid_expiration", "expires_at must be in the future")
+                .into_response()
+        }
+        (Some(expires_at), None) => Some(expires_at),
+        (None, Some(ttl)) => match i64::try_from(ttl)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .and_then(|ttl| now.checked_add_signed(ttl))
+        {
+            Some(expires_at) if ttl > 0 => Some(expires_at),
+            _ => {
+                return ApiError::bad_request("invalid_expiration", "ttl is out of range")
+                    .into_response()
+            }
+        },
+        (None, None) => None,
+    };
+
+    if let Some(rate_limit) = &key.rate_limit {
+        if rate_limit.requests == 0
+            || rate_limit.period_seconds == 0
+            || i64::try_from(rate_limit.period_seconds)
+                .ok()
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|period| now.checked_add_signed(period))
+                .is_none()
+        {
+            return ApiError::bad_request("invalid_rate_limit", "rate_limit is out of range")
+                .into_response();
+        }
+    }
+
+    if key
+        .monthly_quota
+        .is_some_and(|quota| quota == 0 || i64::try_from(quota).is_err())
+    {
+        return ApiError::bad_request("invalid_monthly_quota", "monthly_quota is out of range")
+            .into_response();
+    }
+
+    let mut scopes = key.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let invalid_scopes = scopes
+        .iter()
+        .filter(|scope| !app_state.allowed_scopes.contains(*scope))
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    if !invalid_scopes.is_empty() {
+        return ApiError::bad_request(
+            "invalid_scopes",
+            format!("Invalid scopes: {}", invalid_scopes.join(", ")),
+        )
+        .into_response();
+    }
+
+    let secret = app_state.secret_generator.generate().await;
     let api_key = ApiKey {
         id: Uuid::new_v4(),
-        name: key.name.clone(),
-        secret: app_state.secret_generator.generate().await,
+        name: key.name,
+        secret: app_state.secret_hasher.hash(&secret).await,
+        expires_at,
+        scopes,
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: key.rate_limit,
+        monthly_quota: key.monthly_quota,
     };
 
     match app_state
         .storage_adapter
-        .create_key(&token_data.claims.sub, api_key.clone())
+        .create_key(&owner, api_key.clone())
         .await
     {
-        Ok(_) => Json(api_key).into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to create key: {:?}", e),
-        )
-            .into_response(),
+        Ok(_) => {
+            app_state
+                .notify(
+                    WebhookEventType::KeyCreated,
+                    &owner,
+                    api_key.id,
+                    Some(&api_key),
+                )
+                .await;
+            (
+                Extension(AuditedKey(api_key.id)),
+                Json(ApiKey { secret, ..api_key }),
+            )
+                .into_response()
+        }
+        Err(e) => ApiError::storage("Failed to create key", e).into_response(),
     }
 }
 
-async fn list_keys(State(app_state): State<AppState>, token_data: Token) -> impl IntoResponse {
-    match app_state
-        .storage_adapter
-        .list_keys(&token_data.claims.sub)
-        .await
-    {
+#[utoipa::path(
+    get,
+    path = "/keys",
+    tag = "keys",
+    responses(
+        (status = 200, description = "The keys, without their secrets", body = Vec<ProtectedApiKey>),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id, admin.actor = admin.as_ref().map(|Extension(AdminActor(actor))| actor.as_str())))]
+async fn list_keys(
+    State(app_state): State<AppState>,
+    Extension(owner): Extension<Owner>,
+    admin: Option<Extension<AdminActor>>,
+) -> impl IntoResponse {
+    match app_state.storage_adapter.list_keys(&owner).await {
         Ok(user_keys) => Json(
             user_keys
-                .iter()
-                .map(|key| ProtectedApiKey {
-                    id: key.id,
-                    name: key.name.clone(),
-                })
+                .into_iter()
+                .map(ProtectedApiKey::from)
                 .collect::<Vec<ProtectedApiKey>>(),
         )
         .into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to list keys: {:?}", e),
-        )
-            .into_response(),
+        Err(e) => ApiError::storage("Failed to list keys", e).into_response(),
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/keys/{id}",
+    tag = "keys",
+    params(("id" = Uuid, Path, description = "Id of the key")),
+    responses(
+        (status = 204, description = "The key was deleted"),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "There is no such key", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id, key.id = %id, admin.actor = admin.as_ref().map(|Extension(AdminActor(actor))| actor.as_str())))]
 async fn delete_key(
     State(app_state): State<AppState>,
-    token_data: Token,
-    Path(id): Path<Uuid>,
+    Extension(owner): Extension<Owner>,
+    admin: Option<Extension<AdminActor>>,
+    Path(KeyPath { id }): Path<KeyPath>,
 ) -> impl IntoResponse {
-    match app_state
-        .storage_adapter
-        .delete_key(&token_data.claims.sub, id)
-        .await
-    {
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
-        Err(StorageError::NotFound) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to delete key: {:?}", e),
-        )
-            .into_response(),
+    match app_state.storage_adapter.delete_key(&owner, id).await {
+        Ok(_) => {
+            app_state
+                .notify(WebhookEventType::KeyDeleted, &owner, id, None)
+                .await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(StorageError::NotFound) => {
+            ApiError::key_not_found("There is no such key").into_response()
+        }
+        Err(e) => ApiError::storage("Failed to delete key", e).into_response(),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/keys/{id}",
+    tag = "keys",
+    params(("id" = Uuid, Path, description = "Id of the key")),
+    responses(
+        (status = 200, description = "The key, including its new secret", body = ApiKey),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "There is no such key", body = ApiError, content_type = "application/problem+json"),
+        (status = 409, description = "The new secret conflicts with a stored one", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id, key.id = %id, admin.actor = admin.as_ref().map(|Extension(AdminActor(actor))| actor.as_str())))]
 async fn regenerate_key(
     State(app_state): State<AppState>,
-    token_data: Token,
-    Path(id): Path<Uuid>,
+    Extension(owner): Extension<Owner>,
+    admin: Option<Extension<AdminActor>>,
+    Path(KeyPath { id }): Path<KeyPath>,
 ) -> impl IntoResponse {
-    match app_state
-        .storage_adapter
-        .list_keys(&token_data.claims.sub)
-        .await
-    {
+    match app_state.storage_adapter.list_keys(&owner).await {
         Ok(mut user_keys) => {
             if let Some(key) = user_keys.iter_mut().find(|key| key.id == id) {
+                let secret = app_state.secret_generator.generate().await;
                 let mut updated_key = key.clone();
-                updated_key.secret = app_state.secret_generator.generate().await;
+                updated_key.secret = app_state.secret_hasher.hash(&secret).await;
                 match app_state
                     .storage_adapter
-                    .update_key(&token_data.claims.sub, updated_key.clone())
+                    .update_key(&owner, updated_key.clone())
                     .await
                 {
-                    Ok(_) => Json(updated_key).into_response(),
-                    Err(e) => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to update key: {:?}", e),
-                    )
-                        .into_response(),
+                    Ok(_) => {
+                        app_state
+                            .notify(
+                                WebhookEventType::KeyRegenerated,
+                                &owner,
+                                id,
+                                Some(&updated_key),
+                            )
+                            .await;
+                        Json(ApiKey {
+                            secret,
+                            ..updated_key
+                        })
+                        .into_response()
+                    }
+                    Err(e) => ApiError::storage("Failed to update key", e).into_response(),
                 }
             } else {
-                StatusCode::NOT_FOUND.into_response()
+                ApiError::key_not_found("There is no such key").into_response()
             }
         }
-        Err(StorageError::NotFound) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to regenerate key: {:?}", e),
-        )
-            .into_response(),
+        Err(StorageError::NotFound) => {
+            ApiError::key_not_found("There is no such key").into_response()
+        }
+        Err(e) => ApiError::storage("Failed to regenerate key", e).into_response(),
     }
 }
 
-async fn lookup_key(
-    State(app_state): State<AppState>,
-    token_data: Token,
-    Json(lookup): Json<LookupSecret>,
-) -> impl IntoResponse {
-    match app_state
-        .storage_adapter
-        .lookup_key(&token_data.claims.sub, &lookup.secret)
-        .await
-    {
-        Ok(Some(key)) => Json(ProtectedApiKey {
-            id: key.id,
-            name: key.name,
-        })
-        .into_response(),
-        Ok(None) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to lookup key: {:?}", e),
-        )
-            .into_response(),
-    }
+/// Path of the routes acting on a single key, which may also hold the id of the
+/// organization that owns it.
+#[derive(serde::Deserialize)]
+struct KeyPath {
+    id: Uuid,
 }
 
-async fn healthz() -> impl IntoResponse {
-    axum::http::StatusCode::OK
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UsageQuery {
+    /// Calendar month to report on, as `YYYY-MM`. Defaults to the current one.
+    month: Option<String>,
 }
 
-pub fn router(
-    auth_provider: Arc<dyn AuthProvider>,
-    storage_adapter: Arc<dyn StorageAdapter>,
-    secret_generator: Arc<dyn SecretGenerator>,
-) -> Router {
-    let app_state = AppState {
-        storage_adapter,
-        secret_generator,
+#[utoipa::path(
+    get,
+    path = "/keys/{id}/usage",
+    tag = "keys",
+    params(("id" = Uuid, Path, description = "Id of the key"), UsageQuery),
+    responses(
+        (status = 200, description = "Usage of the key over the month", body = KeyUsage),
+        (status = 400, description = "The month is malformed", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "There is no such key", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id, key.id = %id))]
+async fn get_key_usage(
+    State(app_state): State<AppState>,
+    Extension(owner): Extension<Owner>,
+    Path(KeyPath { id }): Path<KeyPath>,
+    Query(query): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let date = match query.month {
+        Some(month) => match NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => {
+                return ApiError::bad_request("invalid_month", "month must be formatted as YYYY-MM")
+                    .into_response()
+            }
+        },
+        None => Utc::now().date_naive(),
+    };
+    let (from, until) = calendar_month(date);
+
+    let key = match app_state.storage_adapter.list_keys(&owner).await {
+        Ok(user_keys) => match user_keys.into_iter().find(|key| key.id == id) {
+            Some(key) => key,
+            None => return ApiError::key_not_found("There is no such key").into_response(),
+        },
+        Err(StorageError::NotFound) => {
+            return ApiError::key_not_found("There is no such key").into_response()
+        }
+        Err(e) => return ApiError::storage("Failed to get usage", e).into_response(),
     };
 
-    Router::new()
-        .route("/keys", post(create_key))
-        .route("/keys", get(list_keys))
-        .route("/keys/:id", delete(delete_key))
-        .route("/keys/:id", post(regenerate_key))
-        .route("/lookup", post(lookup_key))
-        .with_state(app_state)
-        .layer(middleware::from_fn_with_state(
-            auth_provider,
-            auth_middleware,
-        ))
-        .route("/healthz", get(healthz))
+    match app_state.storage_adapter.get_usage(id, from, until).await {
+        Ok(daily) => {
+            let used = daily.iter().map(|usage| usage.requests).sum::<u64>();
+            Json(KeyUsage {
+                key_id: id,
+                from,
+                until,
+                monthly_quota: key.monthly_quota,
+                used,
+                remaining: key.monthly_quota.map(|quota| quota.saturating_sub(used)),
+                daily,
+            })
+            .into_response()
+        }
+        Err(e) => ApiError::storage("Failed to get usage", e).into_response(),
+    }
 }
 
-pub mod uuid_secret_generator {
-    use std::sync::Arc;
+#[utoipa::path(
+    post,
+    path = "/lookup",
+    tag = "keys",
+    request_body = LookupSecret,
+    responses(
+        (status = 200, description = "The key of the user, or of one of their organizations, with the secret", body = LookedUpApiKey),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 402, description = "The key has used up its monthly quota", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "No key has the secret", body = ApiError, content_type = "application/problem+json"),
+        (status = 429, description = "The key is over its rate limit", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id))]
+async fn lookup_key(
+    State(app_state): State<AppState>,
+    Extension(owner): Extension<Owner>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(lookup): Json<LookupSecret>,
+) -> impl IntoResponse {
+    if !app_state.is_well_formed(&lookup.secret) {
+        app_state.metrics.record_lookup("lookup_key", "miss");
+        return ApiError::key_not_found("No key has the secret").into_response();
+    }
 
-    use axum::async_trait;
-    use uuid::Uuid;
+    let digest = app_state.secret_hasher.hash(&lookup.secret).await;
+
+    match app_state.lookup_key(&owner, &claims, &digest).await {
+        Ok(Some((owner, key))) if constant_time_eq(&digest, &key.secret) => {
+            let (rate_limit_status, quota_status) =
+                match app_state.take_request("lookup_key", &key).await {
+                    Ok(statuses) => statuses,
+                    Err(error) => return (Extension(AuditedKey(key.id)), error).into_response(),
+                };
+            app_state
+                .last_used_recorder
+                .record(KeyUse {
+                    owner,
+                    key_id: key.id,
+                    used_at: Utc::now(),
+                    client_ip: lookup.client_ip,
+                })
+                .await;
+            app_state.metrics.record_lookup("lookup_key", "hit");
+            (
+                Extension(AuditedKey(key.id)),
+                rate_limit_headers(rate_limit_status.as_ref()),
+                Json(LookedUpApiKey {
+                    key: key.into(),
+                    rate_limit_status,
+                    quota_status,
+                }),
+            )
+                .into_response()
+        }
+        Ok(_) => {
+            app_state.metrics.record_lookup("lookup_key", "miss");
+            ApiError::key_not_found("No key has the secret").into_response()
+        }
+        Err(e) => ApiError::storage("Failed to lookup key", e).into_response(),
+    }
+}
 
-    use crate::SecretGenerator;
+#[utoipa::path(
+    post,
+    path = "/verify",
+    tag = "service",
+    request_body = LookupSecret,
+    responses(
+        (status = 200, description = "The key with the secret, and its owner", body = VerifiedApiKey),
+        (status = 401, description = "The service credential is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 402, description = "The key has used up its monthly quota", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "No key has the secret", body = ApiError, content_type = "application/problem+json"),
+        (status = 429, description = "The key is over its rate limit", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("service" = [])),
+)]
+#[tracing::instrument(skip_all)]
+async fn verify_key(
+    State(app_state): State<AppState>,
+    Json(lookup): Json<LookupSecret>,
+) -> impl IntoResponse {
+    if !app_state.is_well_formed(&lookup.secret) {
+        app_state.metrics.record_lookup("verify_key", "miss");
+        return ApiError::key_not_found("No key has the secret").into_response();
+    }
 
-    pub struct UuidSecretGenerator;
+    let digest = app_state.secret_hasher.hash(&lookup.secret).await;
 
-    impl UuidSecretGenerator {
-        pub fn new() -> Arc<Self> {
-            Arc::new(Self)
+    match app_state
+        .storage_adapter
+        .lookup_key_across_users(&digest)
+        .await
+    {
+        Ok(Some((owner, key))) if constant_time_eq(&digest, &key.secret) => {
+            let (rate_limit_status, quota_status) =
+                match app_state.take_request("verify_key", &key).await {
+                    Ok(statuses) => statuses,
+                    Err(error) => return error.into_response(),
+                };
+            app_state
+                .last_used_recorder
+                .record(KeyUse {
+                    owner: owner.clone(),
+                    key_id: key.id,
+                    used_at: Utc::now(),
+                    client_ip: lookup.client_ip,
+                })
+                .await;
+            app_state.metrics.record_lookup("verify_key", "hit");
+            (
+                rate_limit_headers(rate_limit_status.as_ref()),
+                Json(VerifiedApiKey {
+                    owner,
+                    key: key.into(),
+                    rate_limit_status,
+                    quota_status,
+                }),
+            )
+                .into_response()
         }
-    }
-
-    #[async_trait]
-    impl SecretGenerator for UuidSecretGenerator {
-        async fn generate(&self) -> String {
-            Uuid::new_v4().to_string()
+        Ok(_) => {
+            app_state.metrics.record_lookup("verify_key", "miss");
+            ApiError::key_not_found("No key has the secret").into_response()
         }
+        Err(e) => ApiError::storage("Failed to verify key", e).into_response(),
     }
 }
 
-pub mod in_memory_storage {
-    use std::{collections::HashMap, sync::Arc};
-
-    use axum::async_trait;
-    use tokio::sync::Mutex;
-    use uuid::Uuid;
+/// Finds the key with a secret regardless of its owner, for admins tracking
+/// down leaked keys. Unlike `verify_key`, this neither counts as a use of the
+/// key nor is subject to its limits.
+#[utoipa::path(
+    post,
+    path = "/admin/keys/search",
+    tag = "admin",
+    request_body = KeySearch,
+    responses(
+        (status = 200, description = "The key with the secret, and its owner", body = OwnedApiKey),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 403, description = "The token was not granted the admin role", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "No key has the secret", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Storage failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 503, description = "Storage is unavailable, retry after the Retry-After header", body = ApiError, content_type = "application/problem+json"),
+        (status = 504, description = "Storage timed out", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(admin.actor = %admin.0))]
+async fn search_keys(
+    State(app_state): State<AppState>,
+    Extension(admin): Extension<AdminActor>,
+    Json(search): Json<KeySearch>,
+) -> impl IntoResponse {
+    if !app_state.is_well_formed(&search.secret) {
+        return ApiError::key_not_found("No key has the secret").into_response();
+    }
 
-    use crate::{ApiKey, StorageAdapter, StorageError};
+    let digest = app_state.secret_hasher.hash(&search.secret).await;
 
-    pub struct InMemoryStorage {
-        keys: Arc<Mutex<HashMap<String, Vec<ApiKey>>>>,
+    match app_state
+        .storage_adapter
+        .lookup_key_across_users(&digest)
+        .await
+    {
+        Ok(Some((owner, key))) if constant_time_eq(&digest, &key.secret) => (
+            Extension(AuditedKey(key.id)),
+            Json(OwnedApiKey {
+                owner,
+                key: key.into(),
+            }),
+        )
+            .into_response(),
+        Ok(_) => ApiError::key_not_found("No key has the secret").into_response(),
+        Err(e) => ApiError::storage("Failed to search keys", e).into_response(),
     }
+}
 
-    impl InMemoryStorage {
-        pub fn new() -> Arc<Self> {
-            Arc::new(InMemoryStorage {
-                keys: Arc::new(Mutex::new(HashMap::new())),
-            })
-        }
+/// `RateLimit-*` headers describing `status`, for gateways to pass on, plus
+/// `Retry-After` once the limit has been exceeded.
+fn rate_limit_headers(status: Option<&RateLimitStatus>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let Some(status) = status else {
+        return headers;
+    };
+
+    let now = Utc::now();
+    let seconds_until =
+        |at: DateTime<Utc>| ((at - now).num_milliseconds().max(0) as u64).div_ceil(1000);
+    headers.insert("ratelimit-limit", status.limit.into());
+    headers.insert("ratelimit-remaining", status.remaining.into());
+    headers.insert("ratelimit-reset", seconds_until(status.reset_at).into());
+    if let Some(retry_at) = status.retry_at {
+        headers.insert(header::RETRY_AFTER, seconds_until(retry_at).into());
     }
+    headers
+}
 
-    #[async_trait]
-    impl StorageAdapter for InMemoryStorage {
-        async fn create_key(&self, user_id: &str, key: ApiKey) -> Result<(), StorageError> {
-            let mut keys = self.keys.lock().await;
-            keys.entry(user_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(key);
-            Ok(())
+async fn service_auth_middleware(
+    State(service_authenticator): State<Arc<dyn ServiceAuthenticator>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match bearer_token(request.headers()) {
+        Some(credential) if service_authenticator.authenticate(credential).await => {
+            next.run(request).await
         }
+        _ => ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_service_credential",
+            "The service credential is missing or invalid",
+        )
+        .into_response(),
+    }
+}
 
-        async fn list_keys(&self, user_id: &str) -> Result<Vec<ApiKey>, StorageError> {
-            let keys = self.keys.lock().await;
-            Ok(keys.get(user_id).cloned().unwrap_or_default())
+/// Verifies the bearer token of the request with the [`AuthProvider`], and
+/// makes its [`TokenClaims`] available to handlers.
+async fn authenticate(
+    State(auth_provider): State<Arc<dyn AuthProvider>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = bearer_token(request.headers()).map(str::to_string) else {
+        return ApiError::from_status(
+            StatusCode::UNAUTHORIZED,
+            Some("The bearer token is missing".to_string()),
+        )
+        .into_response();
+    };
+    let token_data = match auth_provider.verify(&token).await {
+        Ok(token_data) => token_data,
+        Err(e) => {
+            tracing::debug!("Rejected bearer token: {:?}", e);
+            return ApiError::from_status(
+                StatusCode::UNAUTHORIZED,
+                Some("The bearer token is invalid".to_string()),
+            )
+            .into_response();
         }
+    };
 
-        async fn delete_key(&self, user_id: &str, key_id: Uuid) -> Result<(), StorageError> {
-            let mut keys = self.keys.lock().await;
-            if let Some(user_keys) = keys.get_mut(user_id) {
-                if let Some(index) = user_keys.iter().position(|key| key.id == key_id) {
-                    user_keys.remove(index);
-                    Ok(())
-                } else {
-                    Err(StorageError::NotFound)
-                }
-            } else {
-                Err(StorageError::NotFound)
-            }
-        }
+    let claims = token_claims(auth_provider.as_ref(), &token, token_data).await;
+    request.extensions_mut().insert(claims);
+    next.run(request).await
+}
 
-        async fn update_key(&self, user_id: &str, key: ApiKey) -> Result<(), StorageError> {
-            let mut keys = self.keys.lock().await;
-            if let Some(user_keys) = keys.get_mut(user_id) {
-                if let Some(existing_key) = user_keys.iter_mut().find(|k| k.id == key.id) {
-                    *existing_key = key;
-                    Ok(())
-                } else {
-                    Err(StorageError::NotFound)
-                }
-            } else {
-                Err(StorageError::NotFound)
-            }
+/// Returns every claim of `token`, which `auth_provider` verified as
+/// `token_data`. The claims [`Claims`] leaves out are only read once the
+/// token's signature has been checked against one of the provider's keys, so
+/// tokens that are not JWTs signed by the provider only carry `sub` and `exp`.
+async fn token_claims(
+    auth_provider: &dyn AuthProvider,
+    token: &str,
+    token_data: jsonwebtoken::TokenData<Claims>,
+) -> TokenClaims {
+    let mut claims = serde_json::Map::from_iter([
+        ("sub".to_string(), token_data.claims.sub.into()),
+        ("exp".to_string(), token_data.claims.exp.into()),
+    ]);
+
+    let jwk_set = match auth_provider.jwk_set().await {
+        Ok(jwk_set) => jwk_set,
+        Err(e) => {
+            tracing::warn!("Failed to get the keys of the auth provider: {:?}", e);
+            return TokenClaims(claims);
         }
+    };
+    let jwks = match &token_data.header.kid {
+        Some(kid) => jwk_set.find(kid).into_iter().collect(),
+        None => jwk_set.keys.iter().collect::<Vec<_>>(),
+    };
 
-        async fn lookup_key(
-            &self,
-            user_id: &str,
-            secret: &str,
-        ) -> Result<Option<ApiKey>, StorageError> {
-            let keys = self.keys.lock().await;
-            Ok(keys
-                .get(user_id)
-                .and_then(|user_keys| user_keys.iter().find(|key| key.secret == secret))
-                .cloned())
-        }
+    // Expiry and audience have already been validated by the provider.
+    let mut validation = jsonwebtoken::Validation::new(token_data.header.alg);
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    if let Some(token_data) = jwks
+        .into_iter()
+        .filter_map(|jwk| jsonwebtoken::DecodingKey::from_jwk(jwk).ok())
+        .find_map(|key| {
+            jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+                token,
+                &key,
+                &validation,
+            )
+            .ok()
+        })
+    {
+        claims.extend(token_data.claims);
     }
+
+    TokenClaims(claims)
 }
 
-#[cfg(test)]
-mod tests {
-    use axum::async_trait;
-    use axum_auth_provider::{AuthError, Claims};
-    use axum_test::{TestResponse, TestServer};
-    use in_memory_storage::InMemoryStorage;
-    use jsonwebtoken::{jwk::JwkSet, TokenData};
-    use uuid_secret_generator::UuidSecretGenerator;
+/// Returns the credential of an `Authorization: Bearer` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let (scheme, credential) = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| credential.trim())
+        .filter(|credential| !credential.is_empty())
+}
+
+/// Acts on the keys of the user who made the request, as resolved from their
+/// token's claims.
+async fn user_owner(
+    State(app_state): State<AppState>,
+    Extension(claims): Extension<TokenClaims>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(user_id) = app_state.owner_resolver.resolve(&claims).await else {
+        return ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unknown_owner",
+            "The bearer token does not identify a user",
+        )
+        .into_response();
+    };
+    request.extensions_mut().insert(Owner::user(user_id));
+    next.run(request).await
+}
 
-    use super::*;
+#[derive(serde::Deserialize)]
+struct OrganizationPath {
+    organization_id: String,
+}
 
-    struct TestAuthProvider;
+/// Acts on the keys of the organization in the path instead, provided the user
+/// is one of its admins.
+async fn organization_owner(
+    State(app_state): State<AppState>,
+    Path(OrganizationPath { organization_id }): Path<OrganizationPath>,
+    Extension(owner): Extension<Owner>,
+    Extension(claims): Extension<TokenClaims>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(organization_directory) = &app_state.organization_directory else {
+        return ApiError::from_status(StatusCode::NOT_FOUND, None).into_response();
+    };
 
-    impl TestAuthProvider {
-        fn new() -> Arc<Self> {
-            Arc::new(Self)
+    match organization_directory.memberships(&owner.id, &claims).await {
+        Ok(memberships)
+            if memberships.iter().any(|membership| {
+                membership.organization_id == organization_id
+                    && membership.role == OrganizationRole::Admin
+            }) =>
+        {
+            request
+                .extensions_mut()
+                .insert(Owner::organization(organization_id));
+            next.run(request).await
         }
+        Ok(_) => ApiError::new(
+            StatusCode::FORBIDDEN,
+            "not_organization_admin",
+            "The user is not an admin of the organization",
+        )
+        .into_response(),
+        Err(e) => ApiError::storage("Failed to check organization membership", e).into_response(),
     }
+}
 
-    #[async_trait]
-    impl AuthProvider for TestAuthProvider {
-        async fn jwk_set(&self) -> Result<JwkSet, AuthError> {
-            todo!()
-        }
+/// Header set on every response to an admin route, naming the admin who made
+/// the request.
+pub const ADMIN_ACTOR_HEADER: &str = "x-admin-actor";
 
-        async fn verify(&self, token: &str) -> Result<TokenData<Claims>, AuthError> {
-            Ok(TokenData {
-                header: Default::default(),
-                claims: Claims {
-                    sub: token.to_string(),
-                    exp: 0,
-                },
-            })
-        }
+/// Id of the admin making a request, which handlers record in their spans.
+#[derive(Clone)]
+struct AdminActor(String);
+
+/// Rejects requests from tokens that were not granted the admin role, and
+/// logs those that were.
+async fn require_admin(
+    State(admin_role): State<AdminRole>,
+    Extension(owner): Extension<Owner>,
+    Extension(claims): Extension<TokenClaims>,
+    matched_path: MatchedPath,
+    target_user: Option<Path<AdminPath>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !admin_role.is_granted(&claims) {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin_role_required",
+            "The bearer token was not granted the admin role",
+        )
+        .into_response();
     }
 
-    struct TestClient {
-        server: TestServer,
-    }
+    let method = request.method().clone();
+    request
+        .extensions_mut()
+        .insert(AdminActor(owner.id.clone()));
+    let mut response = next.run(request).await;
+    tracing::info!(
+        admin.actor = %owner.id,
+        admin.target_user = target_user.as_ref().map(|Path(AdminPath { user_id })| user_id.as_str()),
+        route = matched_path.as_str(),
+        %method,
+        status = response.status().as_u16(),
+        "Admin request",
+    );
+    response.headers_mut().insert(
+        ADMIN_ACTOR_HEADER,
+        // Ids that are not valid header values are still marked as admin.
+        header::HeaderValue::from_str(&owner.id)
+            .unwrap_or(header::HeaderValue::from_static("unknown")),
+    );
+    response
+}
 
-    impl TestClient {
-        fn new() -> Self {
-            Self {
-                server: TestServer::new(router(
-                    TestAuthProvider::new(),
-                    InMemoryStorage::new(),
-                    UuidSecretGenerator::new(),
-                ))
-                .unwrap(),
-            }
-        }
+#[derive(serde::Deserialize)]
+struct AdminPath {
+    user_id: String,
+}
 
-        async fn create_key(&self, key: InputApiKey, token: &str) -> TestResponse {
-            self.server
-                .post("/keys")
-                .json(&serde_json::to_value(key).unwrap())
-                .add_header("Authorization", &format!("Bearer {}", token))
-                .await
-        }
+/// Acts on the keys of the user in the path instead of the admin's own.
+async fn admin_target(
+    Path(AdminPath { user_id }): Path<AdminPath>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    request.extensions_mut().insert(Owner::user(user_id));
+    next.run(request).await
+}
 
-        async fn list_keys(&self, token: &str) -> TestResponse {
-            self.server
-                .get("/keys")
-                .add_header("Authorization", &format!("Bearer {}", token))
-                .await
-        }
+async fn audit_requests(
+    State(audit_sink): State<Arc<dyn AuditSink>>,
+    Extension(owner): Extension<Owner>,
+    matched_path: MatchedPath,
+    key_id: Option<Path<KeyPath>>,
+    target_user: Option<Path<AdminPath>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let action = match (request.method(), key_route(&matched_path)) {
+        (&Method::POST, "/keys") => AuditAction::Create,
+        (&Method::POST, "/keys/:id" | "/admin/users/:user_id/keys/:id") => AuditAction::Regenerate,
+        (&Method::DELETE, "/keys/:id" | "/admin/users/:user_id/keys/:id") => AuditAction::Delete,
+        (&Method::POST, "/lookup") => AuditAction::Lookup,
+        (&Method::POST, "/admin/keys/search") => AuditAction::Search,
+        _ => return next.run(request).await,
+    };
+    let timestamp = Utc::now();
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let response = next.run(request).await;
+
+    let event = AuditEvent {
+        timestamp,
+        request_id,
+        actor: owner.id,
+        action,
+        key_id: response
+            .extensions()
+            .get::<AuditedKey>()
+            .map(|AuditedKey(key_id)| *key_id)
+            .or(key_id.map(|Path(KeyPath { id })| id)),
+        outcome: response.status().into(),
+        admin: matched_path.as_str().starts_with("/admin/"),
+        target_user: target_user.map(|Path(AdminPath { user_id })| user_id),
+    };
+    if let Err(e) = audit_sink.record(&event).await {
+        tracing::error!("Failed to record audit event: {:?}", e);
+    }
 
-        async fn delete_key(&self, id: Uuid, token: &str) -> TestResponse {
-            self.server
-                .delete(&format!("/keys/{}", id))
-                .add_header("Authorization", &format!("Bearer {}", token))
-                .await
-        }
+    response
+}
 
-        async fn regenerate_key(&self, id: Uuid, token: &str) -> TestResponse {
-            self.server
-                .post(&format!("/keys/{}", id))
-                .add_header("Authorization", &format!("Bearer {}", token))
-                .await
-        }
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1000;
 
-        async fn lookup_key(&self, secret: String, token: &str) -> TestResponse {
-            self.server
-                .post("/lookup")
-                .json(&LookupSecret { secret })
-                .add_header("Authorization", &format!("Bearer {}", token))
-                .await
-        }
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuditQuery {
+    /// How many of the latest events to return, at most 1000. Defaults to 100.
+    limit: Option<usize>,
+}
 
-        async fn healthz(&self) -> TestResponse {
-            self.server.get("/healthz").await
-        }
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "The latest events of requests made by the user or by admins on their keys, oldest first", body = Vec<AuditEvent>),
+        (status = 401, description = "The bearer token is missing or invalid", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "The audit sink failed", body = ApiError, content_type = "application/problem+json"),
+        (status = 501, description = "The audit sink does not support reading events", body = ApiError, content_type = "application/problem+json"),
+    ),
+    security(("bearer" = [])),
+)]
+#[tracing::instrument(skip_all, fields(owner.kind = ?owner.kind, owner.id = %owner.id))]
+async fn list_audit_events(
+    State(audit_sink): State<Arc<dyn AuditSink>>,
+    Extension(owner): Extension<Owner>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .min(MAX_AUDIT_LIMIT);
+    match audit_sink.list_events(&owner.id, limit).await {
+        Ok(Some(events)) => Json(events).into_response(),
+        Ok(None) => ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "audit_not_readable",
+            "The audit sink does not support reading events",
+        )
+        .into_response(),
+        Err(e) => ApiError::storage("Failed to list audit events", e).into_response(),
     }
+}
 
-    #[tokio::test]
-    async fn test_create_key() {
-        let api_key_name = String::from("my api key");
-        let client = TestClient::new();
-        let response = client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
-
-        assert_eq!(response.status_code(), 200);
-        assert_eq!(response.json::<ApiKey>().id.to_string().is_empty(), false);
-        assert_eq!(response.json::<ApiKey>().name, api_key_name.clone());
-        assert_eq!(response.json::<ApiKey>().secret.is_empty(), false);
-    }
+async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = match (request.method(), key_route(&matched_path)) {
+        (&Method::POST, "/keys") => "create_key",
+        (&Method::GET, "/keys") => "list_keys",
+        (&Method::DELETE, "/keys/:id") => "delete_key",
+        (&Method::POST, "/keys/:id") => "regenerate_key",
+        (&Method::GET, "/keys/:id/usage") => "get_key_usage",
+        (&Method::POST, "/lookup") => "lookup_key",
+        (&Method::POST, "/verify") => "verify_key",
+        (&Method::GET, "/admin/users/:user_id/keys") => "admin_list_keys",
+        (&Method::DELETE, "/admin/users/:user_id/keys/:id") => "admin_delete_key",
+        (&Method::POST, "/admin/users/:user_id/keys/:id") => "admin_regenerate_key",
+        (&Method::POST, "/admin/keys/search") => "admin_search_keys",
+        _ => return next.run(request).await,
+    };
 
-    #[tokio::test]
-    async fn test_list_keys() {
-        let client = TestClient::new();
+    let started_at = Instant::now();
+    let response = next.run(request).await;
+    metrics.record_request(route, response.status(), started_at.elapsed());
+    response
+}
 
-        let api_key_name = String::from("my api key 1");
-        client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
-
-        let api_key_name = String::from("my api key 2");
-        client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
+/// The path of a route relative to the owner of the keys it acts on, so that
+/// organization routes are reported like the equivalent user routes.
+fn key_route(matched_path: &MatchedPath) -> &str {
+    let path = matched_path.as_str();
+    path.strip_prefix("/organizations/:organization_id")
+        .unwrap_or(path)
+}
 
-        let response = client.list_keys("test_token").await;
+async fn sweep_expired_keys(storage_adapter: Arc<dyn StorageAdapter>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage_adapter.purge_expired_keys(Utc::now()).await {
+            tracing::error!("Failed to purge expired keys: {:?}", e);
+        }
+    }
+}
 
-        assert_eq!(response.status_code(), 200);
-        assert_eq!(response.json::<Vec<ProtectedApiKey>>().len(), 2);
+async fn flush_last_used(
+    last_used_recorder: Arc<LastUsedRecorder>,
+    storage_adapter: Arc<dyn StorageAdapter>,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = last_used_recorder.flush(storage_adapter.as_ref()).await {
+            tracing::error!("Failed to record last used keys: {:?}", e);
+        }
     }
+}
 
-    #[tokio::test]
-    async fn test_delete_key() {
-        let client = TestClient::new();
+/// Waits for `shutdown`, then marks the server as draining and shuts it down
+/// gracefully.
+async fn drain(
+    shutdown: impl Future<Output = ()>,
+    handle: axum_server::Handle,
+    draining: Arc<AtomicBool>,
+    drain_timeout: Duration,
+) {
+    shutdown.await;
+    tracing::info!(
+        "Shutting down, waiting up to {:?} for requests in flight",
+        drain_timeout
+    );
+    draining.store(true, Ordering::Relaxed);
+    handle.graceful_shutdown(Some(drain_timeout));
+}
 
-        let api_key_name = String::from("my api key");
-        let response = client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
-        let id = response.json::<ApiKey>().id;
+async fn reload_tls(tls: Arc<TlsConfig>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match tls.reload() {
+            Ok(true) => tracing::info!("Reloaded TLS certificate"),
+            Ok(false) => {}
+            Err(e) => tracing::error!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
 
-        let delete_response = client.delete_key(id, "test_token").await;
-        assert_eq!(delete_response.status_code(), 204);
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 500, description = "Metrics could not be rendered", body = ApiError, content_type = "application/problem+json"),
+    ),
+)]
+async fn render_metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    match app_state.storage_adapter.count_keys().await {
+        Ok(count) => app_state.metrics.set_key_count(count),
+        Err(e) => tracing::error!("Failed to count keys: {:?}", e),
+    }
 
-        let list_response = client.list_keys("test_token").await;
-        assert_eq!(list_response.json::<Vec<ProtectedApiKey>>().len(), 0);
+    match app_state.metrics.render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => ApiError::internal("Failed to render metrics", e).into_response(),
     }
+}
 
-    #[tokio::test]
-    async fn test_regenerate_key() {
-        let client = TestClient::new();
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "The server is running"),
+        (status = 503, description = "The server is shutting down", body = ApiError, content_type = "application/problem+json"),
+    ),
+)]
+async fn healthz(State(draining): State<Arc<AtomicBool>>) -> Result<StatusCode, ApiError> {
+    if draining.load(Ordering::Relaxed) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting_down",
+            "The server is shutting down",
+        ));
+    }
+    Ok(StatusCode::OK)
+}
 
-        let api_key_name = String::from("my api key");
-        let create_response = client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
-        let original_key = create_response.json::<ApiKey>();
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "operations",
+    responses((status = 200, description = "The server is running")),
+)]
+async fn livez() -> impl IntoResponse {
+    StatusCode::OK
+}
 
-        let regenerate_response = client.regenerate_key(original_key.id, "test_token").await;
-        assert_eq!(regenerate_response.status_code(), 200);
+/// How long a component may take to respond to a readiness check before it is
+/// considered down.
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
-        let regenerated_key = regenerate_response.json::<ApiKey>();
-        assert_eq!(regenerated_key.id, original_key.id);
-        assert_eq!(regenerated_key.name, original_key.name);
-        assert_ne!(regenerated_key.secret, original_key.secret);
-    }
+#[derive(Clone)]
+struct Probes {
+    storage_adapter: Arc<dyn StorageAdapter>,
+    auth_provider: Arc<dyn AuthProvider>,
+    draining: Arc<AtomicBool>,
+}
 
-    #[tokio::test]
-    async fn test_successful_lookup_key() {
-        let client = TestClient::new();
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
 
-        let api_key_name = String::from("my api key");
-        let create_response = client
-            .create_key(
-                InputApiKey {
-                    name: api_key_name.clone(),
-                },
-                "test_token",
-            )
-            .await;
-        let created_key = create_response.json::<ApiKey>();
+/// The outcome of checking a component that the server depends on.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// How long the check took, in milliseconds.
+    pub latency_ms: f64,
+}
 
-        let lookup_response = client.lookup_key(created_key.secret, "test_token").await;
-        assert_eq!(lookup_response.status_code(), 200);
+/// Whether the server can serve requests. Reasons that a component is down are
+/// logged rather than returned.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Readiness {
+    /// Up when every component is up and the server is not shutting down.
+    pub status: HealthStatus,
+    pub draining: bool,
+    /// Whether storage answers a trivial request.
+    pub storage: ComponentHealth,
+    /// Whether the auth provider can produce the JWK set that tokens are
+    /// verified with.
+    pub auth_provider: ComponentHealth,
+}
 
-        let looked_up_key = lookup_response.json::<ProtectedApiKey>();
-        assert_eq!(looked_up_key.id, created_key.id);
-        assert_eq!(looked_up_key.name, created_key.name);
+async fn check_component<T, E: std::fmt::Debug>(
+    component: &str,
+    check: impl Future<Output = Result<T, E>>,
+) -> ComponentHealth {
+    let started_at = Instant::now();
+    let status = match tokio::time::timeout(READINESS_CHECK_TIMEOUT, check).await {
+        Ok(Ok(_)) => HealthStatus::Up,
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check of {} failed: {:?}", component, e);
+            HealthStatus::Down
+        }
+        Err(_) => {
+            tracing::warn!("Readiness check of {} timed out", component);
+            HealthStatus::Down
+        }
+    };
+    ComponentHealth {
+        status,
+        latency_ms: started_at.elapsed().as_secs_f64() * 1000.0,
     }
+}
 
-    #[tokio::test]
-    async fn test_invalid_lookup_key() {
-        let client = TestClient::new();
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "The server can serve requests", body = Readiness),
+        (status = 503, description = "A component is down or the server is shutting down", body = Readiness),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+async fn readyz(State(probes): State<Probes>) -> impl IntoResponse {
+    let (storage, auth_provider) = tokio::join!(
+        check_component("storage", probes.storage_adapter.health_check()),
+        check_component("the auth provider", probes.auth_provider.jwk_set()),
+    );
+    let draining = probes.draining.load(Ordering::Relaxed);
+    let status = match (draining, storage.status, auth_provider.status) {
+        (false, HealthStatus::Up, HealthStatus::Up) => HealthStatus::Up,
+        _ => HealthStatus::Down,
+    };
 
-        let invalid_lookup_response = client
-            .lookup_key("invalid_secret".to_string(), "test_token")
-            .await;
-        assert_eq!(invalid_lookup_response.status_code(), 404);
+    let readiness = Readiness {
+        status,
+        draining,
+        storage,
+        auth_provider,
+    };
+    match status {
+        HealthStatus::Up => (StatusCode::OK, Json(readiness)),
+        HealthStatus::Down => (StatusCode::SERVICE_UNAVAILABLE, Json(readiness)),
     }
+}
 
-    #[tokio::test]
-    async fn test_healthz() {
-        let client = TestClient::new();
-        let response = client.healthz().await;
-        assert_eq!(response.status_code(), 200);
-    }
+/// The build of the running server.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Version {
+    pub version: String,
+    /// The optional cargo features that the server was built with.
+    pub features: Vec<String>,
+}
 
-    #[tokio::test]
-    async fn test_different_tokens_access_different_keys() {
-        let client = TestClient::new();
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "operations",
+    responses((status = 200, description = "The build of the running server", body = Version)),
+)]
+async fn version() -> Json<Version> {
+    let features = [
+        ("postgres", cfg!(feature = "postgres")),
+        ("redis", cfg!(feature = "redis")),
+        ("sqlite", cfg!(feature = "sqlite")),
+    ];
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: features
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| feature.to_string())
+            .collect(),
+    })
+}
 
-        let user1_key = client
-            .create_key(
-                InputApiKey {
-                    name: "user1_key".to_string(),
-                },
-                "user1_token",
-            )
-            .await
-            .json::<ApiKey>();
-
-        let user2_key = client
-            .create_key(
-                InputApiKey {
-                    name: "user2_key".to_string(),
-                },
-                "user2_token",
-            )
-            .await
-            .json::<ApiKey>();
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "operations",
+    responses((status = 200, description = "This document", content_type = "application/json")),
+)]
+async fn openapi_json(State(openapi): State<Arc<utoipa::openapi::OpenApi>>) -> impl IntoResponse {
+    Json(openapi.as_ref().clone())
+}
 
-        let user1_keys = client
-            .list_keys("user1_token")
-            .await
-            .json::<Vec<ProtectedApiKey>>();
-        assert_eq!(user1_keys.len(), 1);
-        assert_eq!(user1_keys[0].id, user1_key.id);
+/// Routes that are always served.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        lookup_key,
+        healthz,
+        livez,
+        readyz,
+        version,
+        render_metrics,
+        openapi_json
+    ),
+    modifiers(&SecuritySchemes)
+)]
+struct ApiDoc;
+
+/// Routes acting on the keys of the user who made the request, which are also
+/// served for organizations.
+#[derive(OpenApi)]
+#[openapi(paths(create_key, list_keys, delete_key, regenerate_key, get_key_usage))]
+struct KeyRoutesDoc;
+
+/// Key routes also served for admins acting on another user's keys.
+#[derive(OpenApi)]
+#[openapi(paths(list_keys, delete_key, regenerate_key))]
+struct AdminKeyRoutesDoc;
+
+#[derive(OpenApi)]
+#[openapi(paths(search_keys))]
+struct AdminDoc;
+
+#[derive(OpenApi)]
+#[openapi(paths(list_audit_events))]
+struct AuditDoc;
+
+#[derive(OpenApi)]
+#[openapi(paths(verify_key))]
+struct VerifyDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("A token issued by the configured issuer"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "service",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "A service-audience token or the static service credential",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
 
-        let user2_keys = client
-            .list_keys("user2_token")
-            .await
-            .json::<Vec<ProtectedApiKey>>();
-        assert_eq!(user2_keys.len(), 1);
-        assert_eq!(user2_keys[0].id, user2_key.id);
+fn path_parameter(name: &str, description: &str) -> Parameter {
+    ParameterBuilder::new()
+        .name(name)
+        .parameter_in(ParameterIn::Path)
+        .required(Required::True)
+        .description(Some(description))
+        .schema(Some(String::schema()))
+        .build()
+}
 
-        let delete_response = client.delete_key(user2_key.id, "user1_token").await;
-        assert_eq!(delete_response.status_code(), 404);
+/// Documents the routes of `routes` again under `prefix`, as they are when
+/// nested there. Every operation gains the path `parameter` of the prefix, the
+/// `forbidden` response and the `tag`, and its id is prefixed with the tag to
+/// keep it unique.
+fn nest_routes(
+    routes: utoipa::openapi::OpenApi,
+    prefix: &str,
+    parameter: Parameter,
+    forbidden: &str,
+    tag: &str,
+) -> utoipa::openapi::OpenApi {
+    let mut nested = utoipa::openapi::OpenApi::default();
+    nested.components = routes.components;
+    for (path, mut item) in routes.paths.paths {
+        for operation in [&mut item.get, &mut item.post, &mut item.delete]
+            .into_iter()
+            .flatten()
+        {
+            operation.operation_id = operation
+                .operation_id
+                .as_ref()
+                .map(|operation_id| format!("{}_{}", tag, operation_id));
+            operation.tags = Some(vec![tag.to_string()]);
+            operation
+                .parameters
+                .get_or_insert_with(Vec::new)
+                .insert(0, parameter.clone());
+            operation.responses.responses.insert(
+                StatusCode::FORBIDDEN.as_u16().to_string(),
+                ResponseBuilder::new()
+                    .description(forbidden)
+                    .content(
+                        PROBLEM_JSON,
+                        ContentBuilder::new()
+                            .schema(Some(Ref::from_schema_name("ApiError")))
+                            .build(),
+                    )
+                    .build()
+                    .into(),
+            );
+        }
+        nested
+            .paths
+            .paths
+            .insert(format!("{}{}", prefix, path), item);
+    }
+    nested
+}
 
-        let regenerate_response = client.regenerate_key(user1_key.id, "user2_token").await;
-        assert_eq!(regenerate_response.status_code(), 404);
+/// Routes that hash secrets at rest with HMAC-SHA256, keyed by
+/// `secret_pepper`.
+pub fn router(
+    auth_provider: Arc<dyn AuthProvider>,
+    storage_adapter: Arc<dyn StorageAdapter>,
+    secret_generator: Arc<dyn SecretGenerator>,
+    secret_pepper: impl Into<Vec<u8>>,
+) -> Router {
+    ApiKeyServer::builder()
+        .with_auth_provider(auth_provider)
+        .with_storage_adapter(storage_adapter)
+        .with_secret_generator(secret_generator)
+        .with_secret_pepper(secret_pepper)
+        .build()
+        .expect("all required parts are provided")
+        .router()
+}
 
-        let lookup_response = client.lookup_key(user2_key.secret, "user1_token").await;
-        assert_eq!(lookup_response.status_code(), 404);
-    }
+pub fn router_with_secret_hasher(
+    auth_provider: Arc<dyn AuthProvider>,
+    storage_adapter: Arc<dyn StorageAdapter>,
+    secret_generator: Arc<dyn SecretGenerator>,
+    secret_hasher: Arc<dyn SecretHasher>,
+) -> Router {
+    ApiKeyServer::builder()
+        .with_auth_provider(auth_provider)
+        .with_storage_adapter(storage_adapter)
+        .with_secret_generator(secret_generator)
+        .with_secret_hasher(secret_hasher)
+        .build()
+        .expect("all required parts are provided")
+        .router()
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,269 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{async_trait, http::StatusCode};
+use chrono::{DateTime, NaiveDate, Utc};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::{
+    ApiKey, DailyUsage, KeyUse, Owner, RateLimit, RateLimitStatus, StorageAdapter, StorageError,
+};
+
+/// Prometheus metrics of a single server. Each server keeps its own
+/// registry, so that several can run in the same process.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    lookups: IntCounterVec,
+    storage_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+    keys: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        let registry = Registry::new_custom(Some("api_key_server".to_string()), None)
+            .expect("metric prefix is valid");
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status"),
+                &["route", "status"],
+            )
+            .expect("metric is valid"),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "HTTP request latency by route",
+                ),
+                &["route"],
+            )
+            .expect("metric is valid"),
+            lookups: IntCounterVec::new(
+                Opts::new("lookups_total", "Secret lookups by route and result"),
+                &["route", "result"],
+            )
+            .expect("metric is valid"),
+            storage_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "storage_operation_duration_seconds",
+                    "Storage adapter latency by operation",
+                ),
+                &["operation"],
+            )
+            .expect("metric is valid"),
+            storage_errors: IntCounterVec::new(
+                Opts::new(
+                    "storage_errors_total",
+                    "Storage adapter errors by operation and error",
+                ),
+                &["operation", "error"],
+            )
+            .expect("metric is valid"),
+            keys: IntGauge::new("keys", "Keys currently held in storage").expect("metric is valid"),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.lookups.clone()),
+            Box::new(metrics.storage_duration.clone()),
+            Box::new(metrics.storage_errors.clone()),
+            Box::new(metrics.keys.clone()),
+        ] {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Arc::new(metrics)
+    }
+
+    pub fn record_request(&self, route: &str, status: StatusCode, duration: Duration) {
+        self.requests
+            .with_label_values(&[route, status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_lookup(&self, route: &str, result: &str) {
+        self.lookups.with_label_values(&[route, result]).inc();
+    }
+
+    pub fn set_key_count(&self, count: u64) {
+        self.keys.set(count as i64);
+    }
+
+    pub fn render(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+fn error_label(error: &StorageError) -> &'static str {
+    match error {
+        StorageError::NotFound => "not_found",
+        StorageError::Conflict(_) => "conflict",
+        StorageError::Unavailable { .. } => "unavailable",
+        StorageError::Timeout(_) => "timeout",
+        StorageError::InvalidInput(_) => "invalid_input",
+        StorageError::InternalError(_) => "internal_error",
+    }
+}
+
+/// Wraps a [`StorageAdapter`] to time its operations and count its errors.
+pub struct InstrumentedStorage {
+    inner: Arc<dyn StorageAdapter>,
+    metrics: Arc<Metrics>,
+}
+
+impl InstrumentedStorage {
+    pub fn new(inner: Arc<dyn StorageAdapter>, metrics: Arc<Metrics>) -> Arc<Self> {
+        Arc::new(InstrumentedStorage { inner, metrics })
+    }
+
+    async fn observe<T>(
+        &self,
+        operation: &str,
+        future: impl Future<Output = Result<T, StorageError>>,
+    ) -> Result<T, StorageError> {
+        let span = tracing::info_span!(
+            "storage",
+            otel.name = %format_args!("storage {}", operation),
+            otel.status_code = tracing::field::Empty,
+            operation,
+            error = tracing::field::Empty,
+        );
+        let timer = self
+            .metrics
+            .storage_duration
+            .with_label_values(&[operation])
+            .start_timer();
+        let result = future.instrument(span.clone()).await;
+        timer.observe_duration();
+
+        if let Err(e) = &result {
+            span.record("otel.status_code", "ERROR");
+            span.record("error", error_label(e));
+            self.metrics
+                .storage_errors
+                .with_label_values(&[operation, error_label(e)])
+                .inc();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for InstrumentedStorage {
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        self.observe("create_key", self.inner.create_key(owner, key))
+            .await
+    }
+
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        self.observe("list_keys", self.inner.list_keys(owner)).await
+    }
+
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError> {
+        self.observe("delete_key", self.inner.delete_key(owner, key_id))
+            .await
+    }
+
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        self.observe("update_key", self.inner.update_key(owner, key))
+            .await
+    }
+
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        secret: &str,
+    ) -> Result<Option<ApiKey>, StorageError> {
+        self.observe("lookup_key", self.inner.lookup_key(owner, secret))
+            .await
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        self.observe(
+            "lookup_key_across_users",
+            self.inner.lookup_key_across_users(secret),
+        )
+        .await
+    }
+
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError> {
+        self.observe("purge_expired_keys", self.inner.purge_expired_keys(now))
+            .await
+    }
+
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError> {
+        self.observe("record_last_used", self.inner.record_last_used(key_uses))
+            .await
+    }
+
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        self.inner.shutdown().await
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        self.observe("count_keys", self.inner.count_keys()).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.observe("health_check", self.inner.health_check())
+            .await
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        self.observe(
+            "consume_rate_limit",
+            self.inner.consume_rate_limit(key_id, rate_limit, now),
+        )
+        .await
+    }
+
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        self.observe(
+            "increment_usage",
+            self.inner.increment_usage(key_id, monthly_quota, now),
+        )
+        .await
+    }
+
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError> {
+        self.observe("refund_usage", self.inner.refund_usage(key_id, now))
+            .await
+    }
+
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        self.observe("get_usage", self.inner.get_usage(key_id, from, until))
+            .await
+    }
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use axum::async_trait;
+
+use crate::{Membership, OrganizationDirectory, OrganizationRole, StorageError, TokenClaims};
+
+/// Reads memberships from the claims of users' tokens.
+pub struct ClaimOrganizationDirectory {
+    organization_claim: String,
+    role_claim: Option<String>,
+}
+
+impl ClaimOrganizationDirectory {
+    /// Users belong to the organizations named by `organization_claim`,
+    /// which holds a string or an array of strings. They are admins of all
+    /// of them if `role_claim` holds `admin`, or an array including it, and
+    /// members otherwise.
+    pub fn new(organization_claim: impl Into<String>, role_claim: Option<String>) -> Arc<Self> {
+        Arc::new(ClaimOrganizationDirectory {
+            organization_claim: organization_claim.into(),
+            role_claim,
+        })
+    }
+}
+
+#[async_trait]
+impl OrganizationDirectory for ClaimOrganizationDirectory {
+    async fn memberships(
+        &self,
+        _user_id: &str,
+        claims: &TokenClaims,
+    ) -> Result<Vec<Membership>, StorageError> {
+        let role = match &self.role_claim {
+            Some(role_claim) if claims.strings(role_claim).contains(&"admin") => {
+                OrganizationRole::Admin
+            }
+            _ => OrganizationRole::Member,
+        };
+        Ok(claims
+            .strings(&self.organization_claim)
+            .into_iter()
+            .map(|organization_id| Membership {
+                organization_id: organization_id.to_string(),
+                role,
+            })
+            .collect())
+    }
+}
+
+/// A row of the membership table of a [`StaticOrganizationDirectory`].
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct OrganizationMember {
+    pub user_id: String,
+    pub organization_id: String,
+    pub role: OrganizationRole,
+}
+
+/// Looks memberships up in a fixed table.
+pub struct StaticOrganizationDirectory {
+    memberships: HashMap<String, Vec<Membership>>,
+}
+
+impl StaticOrganizationDirectory {
+    pub fn new(members: impl IntoIterator<Item = OrganizationMember>) -> Arc<Self> {
+        let mut memberships: HashMap<String, Vec<Membership>> = HashMap::new();
+        for member in members {
+            memberships
+                .entry(member.user_id)
+                .or_default()
+                .push(Membership {
+                    organization_id: member.organization_id,
+                    role: member.role,
+                });
+        }
+        Arc::new(StaticOrganizationDirectory { memberships })
+    }
+
+    /// Reads the table from a JSON array of [`OrganizationMember`]s.
+    pub fn load(path: impl AsRef<Path>) -> Result<Arc<Self>, StorageError> {
+        let members = std::fs::read_to_string(path).map_err(internal_error)?;
+        let members: Vec<OrganizationMember> =
+            serde_json::from_str(&members).map_err(internal_error)?;
+        Ok(Self::new(members))
+    }
+}
+
+#[async_trait]
+impl OrganizationDirectory for StaticOrganizationDirectory {
+    async fn memberships(
+        &self,
+        user_id: &str,
+        _claims: &TokenClaims,
+    ) -> Result<Vec<Membership>, StorageError> {
+        Ok(self.memberships.get(user_id).cloned().unwrap_or_default())
+    }
+}
+
+fn internal_error(e: impl ToString) -> StorageError {
+    StorageError::InternalError(e.to_string())
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+
+use crate::{OwnerResolver, TokenClaims};
+
+/// Identifies users by the value of a single claim, such as `sub`, `azp` or
+/// `client_id`.
+pub struct ClaimOwnerResolver {
+    claim: String,
+}
+
+impl ClaimOwnerResolver {
+    pub fn new(claim: impl Into<String>) -> Arc<Self> {
+        Arc::new(ClaimOwnerResolver {
+            claim: claim.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl OwnerResolver for ClaimOwnerResolver {
+    async fn resolve(&self, claims: &TokenClaims) -> Option<String> {
+        claim_value(claims, &self.claim)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Claim(String),
+}
+
+/// Identifies users by a template in which every `{claim}` placeholder is
+/// replaced by the value of that claim, such as `{tenant_id}/{client_id}`.
+pub struct TemplateOwnerResolver {
+    segments: Vec<Segment>,
+}
+
+impl TemplateOwnerResolver {
+    pub fn new(template: &str) -> Result<Arc<Self>, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            let Some(len) = rest[start..]
+                .strip_prefix('{')
+                .and_then(|placeholder| placeholder.find('}'))
+            else {
+                return Err(format!("Unbalanced braces in owner template: {}", template));
+            };
+            let claim = &rest[start + 1..start + 1 + len];
+            if claim.is_empty() || claim.contains('{') {
+                return Err(format!(
+                    "Invalid placeholder in owner template: {}",
+                    template
+                ));
+            }
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            segments.push(Segment::Claim(claim.to_string()));
+            rest = &rest[start + len + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        if !segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Claim(_)))
+        {
+            return Err("Owner template must reference at least one claim".to_string());
+        }
+
+        Ok(Arc::new(TemplateOwnerResolver { segments }))
+    }
+}
+
+#[async_trait]
+impl OwnerResolver for TemplateOwnerResolver {
+    async fn resolve(&self, claims: &TokenClaims) -> Option<String> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => Some(literal.clone()),
+                Segment::Claim(claim) => claim_value(claims, claim),
+            })
+            .collect()
+    }
+}
+
+/// Returns the claim `name` if it is a non-empty string or a number.
+fn claim_value(claims: &TokenClaims, name: &str) -> Option<String> {
+    match claims.get(name)? {
+        serde_json::Value::String(value) if !value.is_empty() => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,577 @@
+use std::{error::Error, sync::Arc};
+
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_postgres::{Config, Pool, PoolConfig, PoolError, Runtime};
+use rustls::{crypto::ring, ClientConfig, RootCertStore};
+use tokio_postgres::{error::SqlState, Row};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use uuid::Uuid;
+
+use crate::{
+    calendar_month, ApiKey, DailyUsage, KeyUse, Owner, RateLimit, RateLimitStatus, StorageAdapter,
+    StorageError, TokenBucket,
+};
+
+/// Schema migrations, applied in order. The index of each entry plus one is
+/// the schema version recorded in `api_key_server_schema` once the migration
+/// has run, so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE api_keys (
+        id UUID PRIMARY KEY,
+        owner_type TEXT NOT NULL,
+        owner_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX api_keys_owner_secret ON api_keys (owner_type, owner_id, secret);",
+    "CREATE INDEX api_keys_secret ON api_keys (secret);",
+    "ALTER TABLE api_keys ADD COLUMN expires_at TIMESTAMPTZ;
+    CREATE INDEX api_keys_expires_at ON api_keys (expires_at);",
+    "ALTER TABLE api_keys ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';",
+    "ALTER TABLE api_keys ADD COLUMN last_used_at TIMESTAMPTZ;
+    ALTER TABLE api_keys ADD COLUMN last_used_ip INET;",
+    // Rate limits and uses are tracked whether or not their key still
+    // exists, as in the other adapters, and deleted along with it by
+    // triggers rather than foreign keys.
+    "ALTER TABLE api_keys ADD COLUMN rate_limit_requests BIGINT;
+    ALTER TABLE api_keys ADD COLUMN rate_limit_period_seconds BIGINT;
+    CREATE TABLE rate_limit_buckets (
+        key_id UUID PRIMARY KEY,
+        tokens DOUBLE PRECISION NOT NULL,
+        updated_at TIMESTAMPTZ NOT NULL
+    );
+    CREATE FUNCTION api_keys_delete_rate_limit_bucket() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+    BEGIN
+        DELETE FROM rate_limit_buckets WHERE key_id = OLD.id;
+        RETURN OLD;
+    END
+    $$;
+    CREATE TRIGGER api_keys_delete_rate_limit_bucket AFTER DELETE ON api_keys
+    FOR EACH ROW EXECUTE FUNCTION api_keys_delete_rate_limit_bucket();",
+    "ALTER TABLE api_keys ADD COLUMN monthly_quota BIGINT;
+    CREATE TABLE key_usage (
+        key_id UUID NOT NULL,
+        day DATE NOT NULL,
+        requests BIGINT NOT NULL,
+        PRIMARY KEY (key_id, day)
+    );
+    CREATE FUNCTION api_keys_delete_key_usage() RETURNS trigger LANGUAGE plpgsql AS $$
+    BEGIN
+        DELETE FROM key_usage WHERE key_id = OLD.id;
+        RETURN OLD;
+    END
+    $$;
+    CREATE TRIGGER api_keys_delete_key_usage AFTER DELETE ON api_keys
+    FOR EACH ROW EXECUTE FUNCTION api_keys_delete_key_usage();",
+];
+
+/// Columns read by [`api_key_from_row`].
+const API_KEY_COLUMNS: &str = "id, name, secret, expires_at, scopes, last_used_at, \
+    last_used_ip, rate_limit_requests, rate_limit_period_seconds, monthly_quota";
+
+/// Arbitrary key for the advisory lock that serialises migrations when
+/// several replicas start at the same time.
+const MIGRATION_LOCK_KEY: i64 = 0x6170_695f_6b65_7973;
+
+pub struct PostgresStorage {
+    pool: Pool,
+}
+
+impl PostgresStorage {
+    /// Connects to the database at `url` with a pool of up to `pool_size`
+    /// connections.
+    ///
+    /// Connections use TLS as the URL's `sslmode` asks: `disable` never
+    /// does, `prefer`, the default, does if the server supports it and
+    /// `require` fails if the server does not. The server's certificate is
+    /// verified against the system's root certificates whenever TLS is
+    /// used, so servers with an untrusted certificate need `sslmode=disable`
+    /// or their authority added to the system's roots.
+    pub async fn connect(url: &str, pool_size: usize) -> Result<Arc<Self>, StorageError> {
+        let mut config = Config::new();
+        config.url = Some(url.to_string());
+        config.pool = Some(PoolConfig::new(pool_size));
+
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1), tls_connector()?)
+            .map_err(internal_error)?;
+
+        let storage = PostgresStorage { pool };
+        storage.migrate().await?;
+
+        Ok(Arc::new(storage))
+    }
+
+    async fn migrate(&self) -> Result<(), StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+
+        transaction
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+            .await
+            .map_err(storage_error)?;
+        transaction
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS api_key_server_schema (version BIGINT NOT NULL)",
+            )
+            .await
+            .map_err(storage_error)?;
+
+        let version: i64 = transaction
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0) FROM api_key_server_schema",
+                &[],
+            )
+            .await
+            .map_err(storage_error)?
+            .get(0);
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            transaction
+                .batch_execute(migration)
+                .await
+                .map_err(storage_error)?;
+            transaction
+                .execute(
+                    "INSERT INTO api_key_server_schema (version) VALUES ($1)",
+                    &[&(index as i64 + 1)],
+                )
+                .await
+                .map_err(storage_error)?;
+        }
+
+        transaction.commit().await.map_err(storage_error)
+    }
+}
+
+/// Verifies servers against the system's root certificates, skipping any
+/// that fail to load.
+fn tls_connector() -> Result<MakeRustlsConnect, StorageError> {
+    let native_certs = rustls_native_certs::load_native_certs();
+    for e in &native_certs.errors {
+        tracing::warn!("Failed to load root certificates: {}", e);
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(native_certs.certs);
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(internal_error)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+fn internal_error(e: impl ToString) -> StorageError {
+    StorageError::InternalError(e.to_string())
+}
+
+/// Tells apart the Postgres errors that callers can act on, such as a lost
+/// connection or a unique violation, from those that point at a bug.
+fn storage_error(e: tokio_postgres::Error) -> StorageError {
+    match e.code().map(SqlState::code) {
+        // unique_violation, exclusion_violation
+        Some("23505" | "23P01") => StorageError::Conflict(e.to_string()),
+        // query_canceled, as by statement_timeout, and lock_not_available
+        Some("57014" | "55P03") => StorageError::Timeout(e.to_string()),
+        // connection exceptions, insufficient resources and shutdowns
+        Some(code)
+            if code.starts_with("08")
+                || code.starts_with("53")
+                || matches!(code, "57P01" | "57P02" | "57P03") =>
+        {
+            StorageError::unavailable(e)
+        }
+        // data exceptions, such as values out of range
+        Some(code) if code.starts_with("22") => StorageError::InvalidInput(e.to_string()),
+        None if e.is_closed()
+            || e.source()
+                .is_some_and(|source| source.is::<std::io::Error>()) =>
+        {
+            StorageError::unavailable(e)
+        }
+        _ => internal_error(e),
+    }
+}
+
+fn pool_error(e: PoolError) -> StorageError {
+    match e {
+        PoolError::Backend(e) => storage_error(e),
+        PoolError::Timeout(_) => StorageError::Timeout(e.to_string()),
+        PoolError::Closed => StorageError::unavailable(e),
+        e => internal_error(e),
+    }
+}
+
+fn owner_from_row(row: &Row) -> Result<Owner, StorageError> {
+    Ok(Owner {
+        kind: row
+            .get::<_, &str>("owner_type")
+            .parse()
+            .map_err(internal_error)?,
+        id: row.get("owner_id"),
+    })
+}
+
+fn api_key_from_row(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get("id"),
+        name: row.get("name"),
+        secret: row.get("secret"),
+        expires_at: row.get("expires_at"),
+        scopes: row.get("scopes"),
+        last_used_at: row.get("last_used_at"),
+        last_used_ip: row.get("last_used_ip"),
+        rate_limit: match (
+            row.get::<_, Option<i64>>("rate_limit_requests"),
+            row.get::<_, Option<i64>>("rate_limit_period_seconds"),
+        ) {
+            (Some(requests), Some(period_seconds)) => Some(RateLimit {
+                requests: requests as u32,
+                period_seconds: period_seconds as u64,
+            }),
+            _ => None,
+        },
+        monthly_quota: row
+            .get::<_, Option<i64>>("monthly_quota")
+            .map(|monthly_quota| monthly_quota as u64),
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for PostgresStorage {
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        client
+            .execute(
+                "INSERT INTO api_keys (id, owner_type, owner_id, name, secret, expires_at,
+                scopes, rate_limit_requests, rate_limit_period_seconds, monthly_quota)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                &[
+                    &key.id,
+                    &owner.kind.as_str(),
+                    &owner.id,
+                    &key.name,
+                    &key.secret,
+                    &key.expires_at,
+                    &key.scopes,
+                    &key.rate_limit.map(|rate_limit| rate_limit.requests as i64),
+                    &key.rate_limit
+                        .map(|rate_limit| rate_limit.period_seconds as i64),
+                    &key.monthly_quota.map(|monthly_quota| monthly_quota as i64),
+                ],
+            )
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM api_keys WHERE owner_type = $1 AND owner_id = $2
+                    AND (expires_at IS NULL OR expires_at > now())
+                    ORDER BY created_at, id",
+                    API_KEY_COLUMNS
+                ),
+                &[&owner.kind.as_str(), &owner.id],
+            )
+            .await
+            .map_err(storage_error)?;
+        Ok(rows.iter().map(api_key_from_row).collect())
+    }
+
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+        match transaction
+            .execute(
+                "DELETE FROM api_keys WHERE owner_type = $1 AND owner_id = $2 AND id = $3",
+                &[&owner.kind.as_str(), &owner.id, &key_id],
+            )
+            .await
+            .map_err(storage_error)?
+        {
+            0 => Err(StorageError::NotFound),
+            _ => transaction.commit().await.map_err(storage_error),
+        }
+    }
+
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+        match transaction
+            .execute(
+                "UPDATE api_keys SET name = $1, secret = $2, expires_at = $3, scopes = $4,
+                rate_limit_requests = $5, rate_limit_period_seconds = $6,
+                monthly_quota = $7
+                WHERE owner_type = $8 AND owner_id = $9 AND id = $10",
+                &[
+                    &key.name,
+                    &key.secret,
+                    &key.expires_at,
+                    &key.scopes,
+                    &key.rate_limit.map(|rate_limit| rate_limit.requests as i64),
+                    &key.rate_limit
+                        .map(|rate_limit| rate_limit.period_seconds as i64),
+                    &key.monthly_quota.map(|monthly_quota| monthly_quota as i64),
+                    &owner.kind.as_str(),
+                    &owner.id,
+                    &key.id,
+                ],
+            )
+            .await
+            .map_err(storage_error)?
+        {
+            0 => Err(StorageError::NotFound),
+            _ => transaction.commit().await.map_err(storage_error),
+        }
+    }
+
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        secret: &str,
+    ) -> Result<Option<ApiKey>, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM api_keys
+                    WHERE owner_type = $1 AND owner_id = $2 AND secret = $3
+                    AND (expires_at IS NULL OR expires_at > now())",
+                    API_KEY_COLUMNS
+                ),
+                &[&owner.kind.as_str(), &owner.id, &secret],
+            )
+            .await
+            .map_err(storage_error)?;
+        Ok(row.as_ref().map(api_key_from_row))
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {}, owner_type, owner_id FROM api_keys
+                    WHERE secret = $1 AND (expires_at IS NULL OR expires_at > now())",
+                    API_KEY_COLUMNS
+                ),
+                &[&secret],
+            )
+            .await
+            .map_err(storage_error)?;
+        row.as_ref()
+            .map(|row| Ok((owner_from_row(row)?, api_key_from_row(row))))
+            .transpose()
+    }
+
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        client
+            .execute("DELETE FROM api_keys WHERE expires_at <= $1", &[&now])
+            .await
+            .map_err(storage_error)
+    }
+
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+        let statement = transaction
+            .prepare(
+                "UPDATE api_keys
+                SET last_used_at = $1, last_used_ip = COALESCE($2, last_used_ip)
+                WHERE owner_type = $3 AND owner_id = $4 AND id = $5
+                AND (last_used_at IS NULL OR last_used_at < $1)",
+            )
+            .await
+            .map_err(storage_error)?;
+        for key_use in key_uses {
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        &key_use.used_at,
+                        &key_use.client_ip,
+                        &key_use.owner.kind.as_str(),
+                        &key_use.owner.id,
+                        &key_use.key_id,
+                    ],
+                )
+                .await
+                .map_err(storage_error)?;
+        }
+        transaction.commit().await.map_err(storage_error)
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        let count: i64 = client
+            .query_one("SELECT COUNT(*) FROM api_keys", &[])
+            .await
+            .map_err(storage_error)?
+            .get(0);
+        Ok(count as u64)
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        client
+            .simple_query("SELECT 1")
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+
+        // Start from a full bucket, so concurrent first requests for a key
+        // all lock the same row.
+        transaction
+            .execute(
+                "INSERT INTO rate_limit_buckets (key_id, tokens, updated_at)
+                VALUES ($1, $2, $3) ON CONFLICT (key_id) DO NOTHING",
+                &[&key_id, &(rate_limit.requests as f64), &now],
+            )
+            .await
+            .map_err(storage_error)?;
+        let row = transaction
+            .query_one(
+                "SELECT tokens, updated_at FROM rate_limit_buckets
+                WHERE key_id = $1 FOR UPDATE",
+                &[&key_id],
+            )
+            .await
+            .map_err(storage_error)?;
+
+        let (bucket, status) = TokenBucket::take(
+            Some(TokenBucket {
+                tokens: row.get("tokens"),
+                updated_at: row.get("updated_at"),
+            }),
+            rate_limit,
+            now,
+        );
+        transaction
+            .execute(
+                "UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3
+                WHERE key_id = $1",
+                &[&key_id, &bucket.tokens, &bucket.updated_at],
+            )
+            .await
+            .map_err(storage_error)?;
+        transaction.commit().await.map_err(storage_error)?;
+        Ok(status)
+    }
+
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        let (from, until) = calendar_month(now.date_naive());
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+
+        // Lock the key's usage, so that concurrent uses cannot both fit in
+        // the last remaining request of the quota.
+        transaction
+            .execute(
+                "SELECT pg_advisory_xact_lock(hashtextextended($1::uuid::text, 0))",
+                &[&key_id],
+            )
+            .await
+            .map_err(storage_error)?;
+        let used: i64 = transaction
+            .query_one(
+                "SELECT COALESCE(SUM(requests), 0)::BIGINT FROM key_usage
+                WHERE key_id = $1 AND day >= $2 AND day < $3",
+                &[&key_id, &from, &until],
+            )
+            .await
+            .map_err(storage_error)?
+            .get(0);
+        let used = used as u64;
+        if monthly_quota.is_some_and(|quota| used >= quota) {
+            return Ok((false, used));
+        }
+
+        transaction
+            .execute(
+                "INSERT INTO key_usage (key_id, day, requests) VALUES ($1, $2, 1)
+                ON CONFLICT (key_id, day) DO UPDATE SET requests = key_usage.requests + 1",
+                &[&key_id, &now.date_naive()],
+            )
+            .await
+            .map_err(storage_error)?;
+        transaction.commit().await.map_err(storage_error)?;
+        Ok((true, used + 1))
+    }
+
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut client = self.pool.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(storage_error)?;
+        transaction
+            .execute(
+                "UPDATE key_usage SET requests = requests - 1
+                WHERE key_id = $1 AND day = $2 AND requests > 0",
+                &[&key_id, &now.date_naive()],
+            )
+            .await
+            .map_err(storage_error)?;
+        transaction
+            .execute(
+                "DELETE FROM key_usage WHERE key_id = $1 AND day = $2 AND requests = 0",
+                &[&key_id, &now.date_naive()],
+            )
+            .await
+            .map_err(storage_error)?;
+        transaction.commit().await.map_err(storage_error)
+    }
+
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        let client = self.pool.get().await.map_err(pool_error)?;
+        let rows = client
+            .query(
+                "SELECT day, requests FROM key_usage
+                WHERE key_id = $1 AND day >= $2 AND day < $3 ORDER BY day",
+                &[&key_id, &from, &until],
+            )
+            .await
+            .map_err(storage_error)?;
+        Ok(rows
+            .iter()
+            .map(|row| DailyUsage {
+                date: row.get("day"),
+                requests: row.get::<_, i64>("requests") as u64,
+            })
+            .collect())
+    }
+
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        self.pool.close();
+        Ok(())
+    }
+}
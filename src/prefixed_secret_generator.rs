@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use rand::{rngs::OsRng, seq::SliceRandom};
+
+use crate::SecretGenerator;
+
+pub const DEFAULT_ALPHABET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Generates `<prefix>_<random>_<checksum>` secrets, where the random part
+/// and the CRC32 checksum of everything before it are written with the
+/// configured alphabet. The prefix lets secret scanners recognise leaked
+/// keys, and the checksum lets malformed secrets be rejected offline.
+pub struct PrefixedSecretGenerator {
+    prefix: String,
+    alphabet: Vec<char>,
+    random_len: usize,
+    checksum_len: usize,
+}
+
+impl PrefixedSecretGenerator {
+    pub fn new(
+        prefix: impl Into<String>,
+        entropy_bytes: usize,
+        alphabet: &str,
+    ) -> Result<Arc<Self>, String> {
+        let prefix = prefix.into();
+        if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_graphic()) {
+            return Err("Secret prefix must be non-empty printable ASCII".to_string());
+        }
+        if entropy_bytes == 0 {
+            return Err("Secret entropy must be at least one byte".to_string());
+        }
+
+        let mut alphabet = alphabet.chars().collect::<Vec<char>>();
+        let alphabet_len = alphabet.len();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() != alphabet_len
+            || alphabet.len() < 2
+            || !alphabet.iter().all(|c| c.is_ascii_graphic() && *c != '_')
+        {
+            return Err(
+                "Secret alphabet must have at least two distinct printable ASCII characters \
+                 other than '_'"
+                    .to_string(),
+            );
+        }
+
+        let bits_per_char = (alphabet.len() as f64).log2();
+        Ok(Arc::new(Self {
+            prefix,
+            random_len: (entropy_bytes as f64 * 8.0 / bits_per_char).ceil() as usize,
+            checksum_len: (32.0 / bits_per_char).ceil() as usize,
+            alphabet,
+        }))
+    }
+
+    fn checksum(&self, prefix_and_random: &str) -> String {
+        let base = self.alphabet.len() as u64;
+        let mut checksum = crc32fast::hash(prefix_and_random.as_bytes()) as u64;
+        let mut encoded = vec![self.alphabet[0]; self.checksum_len];
+        for c in encoded.iter_mut().rev() {
+            *c = self.alphabet[(checksum % base) as usize];
+            checksum /= base;
+        }
+        encoded.into_iter().collect()
+    }
+}
+
+#[async_trait]
+impl SecretGenerator for PrefixedSecretGenerator {
+    async fn generate(&self) -> String {
+        let random = (0..self.random_len)
+            .map(|_| {
+                self.alphabet
+                    .choose(&mut OsRng)
+                    .expect("alphabet is not empty")
+            })
+            .collect::<String>();
+        let prefix_and_random = format!("{}_{}", self.prefix, random);
+        let checksum = self.checksum(&prefix_and_random);
+        format!("{}_{}", prefix_and_random, checksum)
+    }
+
+    fn validate_format(&self, secret: &str) -> bool {
+        let Some((prefix_and_random, checksum)) = secret.rsplit_once('_') else {
+            return false;
+        };
+        let Some(random) = prefix_and_random
+            .strip_prefix(&self.prefix)
+            .and_then(|random| random.strip_prefix('_'))
+        else {
+            return false;
+        };
+
+        random.chars().count() == self.random_len
+            && random
+                .chars()
+                .all(|c| self.alphabet.binary_search(&c).is_ok())
+            && checksum == self.checksum(prefix_and_random)
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{RateLimit, RateLimitStatus, RateLimiter, StorageAdapter, StorageError, TokenBucket};
+
+/// Keeps token buckets in process memory, so limits are not shared between
+/// replicas.
+///
+/// A missing bucket is full, so buckets that have refilled are dropped
+/// every sweep interval. Memory then only grows with the keys used within
+/// their rate limit period, whether or not the others were deleted since.
+pub struct InMemoryRateLimiter {
+    buckets: Mutex<Buckets>,
+    sweep_interval: Duration,
+}
+
+struct Buckets {
+    /// Buckets of keys, along with when they will be full again.
+    by_key: HashMap<Uuid, (TokenBucket, DateTime<Utc>)>,
+    swept_at: Instant,
+}
+
+impl InMemoryRateLimiter {
+    pub fn new() -> Arc<Self> {
+        Self::with_sweep_interval(Duration::from_secs(60))
+    }
+
+    /// Drops the buckets that have refilled at most once every
+    /// `sweep_interval`.
+    pub fn with_sweep_interval(sweep_interval: Duration) -> Arc<Self> {
+        Arc::new(InMemoryRateLimiter {
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+            sweep_interval,
+        })
+    }
+
+    /// Counts the keys whose bucket is currently kept.
+    pub async fn tracked_keys(&self) -> usize {
+        self.buckets.lock().await.by_key.len()
+    }
+}
+
+#[async_trait]
+impl RateLimiter for InMemoryRateLimiter {
+    async fn acquire(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+    ) -> Result<RateLimitStatus, StorageError> {
+        let now = Utc::now();
+        let mut buckets = self.buckets.lock().await;
+        if buckets.swept_at.elapsed() >= self.sweep_interval {
+            buckets.by_key.retain(|_, (_, full_at)| *full_at > now);
+            buckets.swept_at = Instant::now();
+        }
+
+        let (bucket, status) = TokenBucket::take(
+            buckets.by_key.get(&key_id).map(|(bucket, _)| *bucket),
+            rate_limit,
+            now,
+        );
+        buckets.by_key.insert(key_id, (bucket, status.reset_at));
+        Ok(status)
+    }
+}
+
+/// Keeps token buckets in a [`StorageAdapter`], so that every replica using
+/// the same storage shares the same limits. A server set up with
+/// [`ApiKeyServerBuilder::with_storage_rate_limiter`](crate::ApiKeyServerBuilder::with_storage_rate_limiter)
+/// builds one over its instrumented storage.
+pub struct StorageRateLimiter {
+    storage_adapter: Arc<dyn StorageAdapter>,
+}
+
+impl StorageRateLimiter {
+    pub fn new(storage_adapter: Arc<dyn StorageAdapter>) -> Arc<Self> {
+        Arc::new(StorageRateLimiter { storage_adapter })
+    }
+}
+
+#[async_trait]
+impl RateLimiter for StorageRateLimiter {
+    async fn acquire(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+    ) -> Result<RateLimitStatus, StorageError> {
+        self.storage_adapter
+            .consume_rate_limit(key_id, rate_limit, Utc::now())
+            .await
+    }
+}
@@ -0,0 +1,669 @@
+//! Redis storage adapter.
+//!
+//! Only the secret index entry of a key expires natively. The key's field in
+//! its owner's hash, its place in the expiration schedule, its last used
+//! fields, its usage and the key count are only cleaned up by
+//! [`RedisStorage::purge_expired_keys`](crate::StorageAdapter::purge_expired_keys),
+//! so the expired key sweeper is required: a server using this adapter must
+//! keep it running, or expired keys pile up in Redis and are still counted
+//! by `count_keys`.
+//!
+//! Every script is passed all of the keys it touches in `KEYS`.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use redis::{aio::ConnectionManager, AsyncCommands, ErrorKind, RedisError, Script};
+use uuid::Uuid;
+
+use crate::{
+    ApiKey, DailyUsage, KeyUse, Owner, OwnerKind, RateLimit, RateLimitStatus, StorageAdapter,
+    StorageError,
+};
+
+/// Adds a key to the per-owner hash and counts it, unless the secret index
+/// already has an entry for its secret.
+///
+/// KEYS[1] is the owner's hash, KEYS[2] the expiration schedule, KEYS[3]
+/// the secret index entry and KEYS[4] the key count.
+///
+/// ARGV[1] is the key id, ARGV[2] the key's member in the schedule, ARGV[3]
+/// and ARGV[4] the serialised key and its index entry, and ARGV[5] its
+/// expiry in milliseconds, or empty if it never expires.
+const CREATE_KEY_SCRIPT: &str = r#"
+    local created
+    if ARGV[5] == '' then
+        created = redis.call('SET', KEYS[3], ARGV[4], 'NX')
+    else
+        created = redis.call('SET', KEYS[3], ARGV[4], 'NX', 'PXAT', ARGV[5])
+    end
+    if not created then
+        return 0
+    end
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+    redis.call('INCR', KEYS[4])
+    if ARGV[5] ~= '' then
+        redis.call('ZADD', KEYS[2], ARGV[5], ARGV[2])
+    end
+    return 1
+"#;
+
+/// Replaces a key in the per-owner hash and moves its secret index entry,
+/// so a stale secret never resolves once the key has been regenerated.
+/// Returns 0 if the key does not exist, -1 if another key already has the
+/// new secret, and -2 if the key changed since it was read.
+///
+/// KEYS[1] is the owner's hash, KEYS[2] the expiration schedule, KEYS[3]
+/// the owner's last used hash, KEYS[4] the key's usage hash and KEYS[5] the
+/// key count, which are only changed on delete. KEYS[6] is the index entry
+/// of the key's current secret and KEYS[7] that of its new secret, which is
+/// omitted to delete the key.
+///
+/// ARGV[1] is the key id, ARGV[2] the key's member in the schedule and
+/// ARGV[3] the serialised key as it was read. ARGV[4], ARGV[5] and ARGV[6]
+/// are the new serialised key, its index entry and its expiry in
+/// milliseconds, or empty if it never expires.
+const REPLACE_KEY_SCRIPT: &str = r#"
+    local existing = redis.call('HGET', KEYS[1], ARGV[1])
+    if not existing then
+        return 0
+    end
+    if existing ~= ARGV[3] then
+        return -2
+    end
+    if KEYS[7] and KEYS[7] ~= KEYS[6] and redis.call('EXISTS', KEYS[7]) == 1 then
+        return -1
+    end
+    redis.call('DEL', KEYS[6])
+    redis.call('ZREM', KEYS[2], ARGV[2])
+    if KEYS[7] then
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[4])
+        if ARGV[6] == '' then
+            redis.call('SET', KEYS[7], ARGV[5])
+        else
+            redis.call('SET', KEYS[7], ARGV[5], 'PXAT', ARGV[6])
+            redis.call('ZADD', KEYS[2], ARGV[6], ARGV[2])
+        end
+    else
+        redis.call('HDEL', KEYS[1], ARGV[1])
+        redis.call('HDEL', KEYS[3], ARGV[1] .. ':at', ARGV[1] .. ':ip')
+        redis.call('DEL', KEYS[4])
+        redis.call('DECR', KEYS[5])
+    end
+    return 1
+"#;
+
+/// Records a key use unless the key is gone or was used more recently.
+///
+/// KEYS[1] is the owner's hash and KEYS[2] their last used hash. ARGV[1] is
+/// the key id, ARGV[2] the time of use in milliseconds and ARGV[3] the
+/// client address, or empty if unknown.
+const RECORD_LAST_USED_SCRIPT: &str = r#"
+    if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then
+        return 0
+    end
+    local last_used_at = redis.call('HGET', KEYS[2], ARGV[1] .. ':at')
+    if last_used_at and tonumber(last_used_at) >= tonumber(ARGV[2]) then
+        return 0
+    end
+    redis.call('HSET', KEYS[2], ARGV[1] .. ':at', ARGV[2])
+    if ARGV[3] ~= '' then
+        redis.call('HSET', KEYS[2], ARGV[1] .. ':ip', ARGV[3])
+    end
+    return 1
+"#;
+
+/// Refills a key's token bucket and takes a token from it if it can.
+///
+/// KEYS[1] is the key's bucket hash. ARGV[1] is the bucket's capacity,
+/// ARGV[2] the refill period and ARGV[3] the current time, both in
+/// milliseconds. The bucket expires once it would have refilled, and the
+/// remaining tokens are returned as a string since Lua numbers are
+/// truncated to integers in replies.
+const CONSUME_RATE_LIMIT_SCRIPT: &str = r#"
+    local capacity = tonumber(ARGV[1])
+    local now = tonumber(ARGV[3])
+    local tokens = capacity
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+    if bucket[1] then
+        local elapsed = math.max(0, now - tonumber(bucket[2]))
+        tokens = math.min(capacity, tonumber(bucket[1]) + elapsed * capacity / tonumber(ARGV[2]))
+    end
+    local allowed = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+        allowed = 1
+    end
+    redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', ARGV[3])
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return {allowed, tostring(tokens)}
+"#;
+
+/// Counts a use of a key unless it has used up its monthly quota.
+///
+/// KEYS[1] is the key's usage hash. ARGV[1] is the month's field, which
+/// holds the month's total, ARGV[2] the day's field, ARGV[3] the quota, or
+/// empty if the key has none, and ARGV[4] how long the hash is kept after
+/// the last use, in milliseconds.
+const INCREMENT_USAGE_SCRIPT: &str = r#"
+    local used = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or '0')
+    if ARGV[3] ~= '' and used >= tonumber(ARGV[3]) then
+        return {0, used}
+    end
+    redis.call('HINCRBY', KEYS[1], ARGV[2], 1)
+    local month_used = redis.call('HINCRBY', KEYS[1], ARGV[1], 1)
+    redis.call('PEXPIRE', KEYS[1], ARGV[4])
+    return {1, month_used}
+"#;
+
+/// Takes back a use of a key counted by the increment script, unless the
+/// day has none left to take back.
+///
+/// KEYS[1] is the key's usage hash. ARGV[1] is the month's field and ARGV[2]
+/// the day's field. Fields left at zero are deleted.
+const REFUND_USAGE_SCRIPT: &str = r#"
+    if tonumber(redis.call('HGET', KEYS[1], ARGV[2]) or '0') <= 0 then
+        return 0
+    end
+    for _, field in ipairs({ARGV[1], ARGV[2]}) do
+        if redis.call('HINCRBY', KEYS[1], field, -1) <= 0 then
+            redis.call('HDEL', KEYS[1], field)
+        end
+    end
+    return 1
+"#;
+
+/// How long the usage of a key is kept after its last use. Usage is
+/// deleted along with its key, but a use counted while the key is being
+/// deleted would otherwise recreate the hash for good.
+const USAGE_RETENTION: Duration = Duration::from_secs(400 * 24 * 60 * 60);
+
+/// Value of the secret index, which is shared by all owners so that keys
+/// can be verified without knowing their owner.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct IndexEntry {
+    owner_type: OwnerKind,
+    owner_id: String,
+    key: ApiKey,
+}
+
+/// A key serialised for storage, along with its secret index entry.
+struct SerializedKey {
+    secret: String,
+    value: String,
+    index_entry: String,
+    expires_at: Option<i64>,
+}
+
+/// Stores each owner's keys in a hash, along with an index by secret.
+///
+/// Expired keys are hidden from lookups and listings, but stay in Redis
+/// until the expired key sweeper purges them, see the module docs.
+pub struct RedisStorage {
+    connection: ConnectionManager,
+    namespace: String,
+    create_key_script: Script,
+    replace_key_script: Script,
+    record_last_used_script: Script,
+    consume_rate_limit_script: Script,
+    increment_usage_script: Script,
+    refund_usage_script: Script,
+}
+
+impl RedisStorage {
+    pub async fn connect(
+        url: &str,
+        namespace: impl Into<String>,
+    ) -> Result<Arc<Self>, StorageError> {
+        let client = redis::Client::open(url).map_err(storage_error)?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(storage_error)?;
+
+        Ok(Arc::new(RedisStorage {
+            connection,
+            namespace: namespace.into(),
+            create_key_script: Script::new(CREATE_KEY_SCRIPT),
+            replace_key_script: Script::new(REPLACE_KEY_SCRIPT),
+            record_last_used_script: Script::new(RECORD_LAST_USED_SCRIPT),
+            consume_rate_limit_script: Script::new(CONSUME_RATE_LIMIT_SCRIPT),
+            increment_usage_script: Script::new(INCREMENT_USAGE_SCRIPT),
+            refund_usage_script: Script::new(REFUND_USAGE_SCRIPT),
+        }))
+    }
+
+    fn owner_key(&self, owner: &Owner) -> String {
+        match owner.kind {
+            OwnerKind::User => format!("{}:users:{}", self.namespace, owner.id),
+            OwnerKind::Organization => {
+                format!("{}:organizations:{}", self.namespace, owner.id)
+            }
+        }
+    }
+
+    fn keys_key(&self, owner: &Owner) -> String {
+        format!("{}:keys", self.owner_key(owner))
+    }
+
+    /// Hash of `<key id>:at` and `<key id>:ip` fields, kept apart from the
+    /// keys so that recording a use does not rewrite them.
+    fn last_used_key(&self, owner: &Owner) -> String {
+        format!("{}:last_used", self.owner_key(owner))
+    }
+
+    fn rate_limit_key(&self, key_id: Uuid) -> String {
+        format!("{}:rate_limits:{}", self.namespace, key_id)
+    }
+
+    /// Hash of `YYYY-MM-DD` fields counting the key's uses on each day, and
+    /// of `YYYY-MM` fields with the total of each month.
+    fn usage_key(&self, key_id: Uuid) -> String {
+        format!("{}:usage:{}", self.namespace, key_id)
+    }
+
+    fn secret_key(&self, secret: &str) -> String {
+        format!("{}:secrets:{}", self.namespace, secret)
+    }
+
+    /// Number of keys across all owners, kept so `count_keys` does not
+    /// have to scan the keyspace.
+    fn key_count_key(&self) -> String {
+        format!("{}:key_count", self.namespace)
+    }
+
+    /// Sorted set of `<key id>:<owner type>:<owner id>` members scored by
+    /// expiry, from which `purge_expired_keys` finds the keys to delete.
+    fn expirations_key(&self) -> String {
+        format!("{}:expirations", self.namespace)
+    }
+
+    fn expiration_member(owner: &Owner, key_id: Uuid) -> String {
+        format!("{}:{}:{}", key_id, owner.kind.as_str(), owner.id)
+    }
+
+    /// Parses a member of the expiration schedule, see
+    /// [`Self::expiration_member`].
+    fn parse_expiration_member(member: &str) -> Option<(Owner, &str)> {
+        let mut parts = member.splitn(3, ':');
+        let key_id = parts.next()?;
+        let kind = parts.next()?.parse().ok()?;
+        let id = parts.next()?.to_string();
+        Some((Owner { kind, id }, key_id))
+    }
+
+    fn serialize(owner: &Owner, key: ApiKey) -> Result<SerializedKey, StorageError> {
+        let expires_at = key
+            .expires_at
+            .map(|expires_at| expires_at.timestamp_millis());
+        let secret = key.secret.clone();
+        let value = serde_json::to_string(&key).map_err(internal_error)?;
+        let index_entry = serde_json::to_string(&IndexEntry {
+            owner_type: owner.kind,
+            owner_id: owner.id.clone(),
+            key,
+        })
+        .map_err(internal_error)?;
+        Ok(SerializedKey {
+            secret,
+            value,
+            index_entry,
+            expires_at,
+        })
+    }
+
+    /// Replaces or deletes a key, retrying if it changes between reading
+    /// its current secret and running the script.
+    async fn replace_key(
+        &self,
+        owner: &Owner,
+        key_id: Uuid,
+        key: Option<SerializedKey>,
+    ) -> Result<(), StorageError> {
+        loop {
+            let existing: Option<String> = self
+                .connection
+                .clone()
+                .hget(self.keys_key(owner), key_id.to_string())
+                .await
+                .map_err(storage_error)?;
+            let Some(existing) = existing else {
+                return Err(StorageError::NotFound);
+            };
+            let existing_secret = serde_json::from_str::<ApiKey>(&existing)
+                .map_err(internal_error)?
+                .secret;
+
+            let mut invocation = self.replace_key_script.prepare_invoke();
+            invocation
+                .key(self.keys_key(owner))
+                .key(self.expirations_key())
+                .key(self.last_used_key(owner))
+                .key(self.usage_key(key_id))
+                .key(self.key_count_key())
+                .key(self.secret_key(&existing_secret))
+                .arg(key_id.to_string())
+                .arg(Self::expiration_member(owner, key_id))
+                .arg(existing);
+            if let Some(key) = &key {
+                invocation
+                    .key(self.secret_key(&key.secret))
+                    .arg(&key.value)
+                    .arg(&key.index_entry)
+                    .arg(
+                        key.expires_at
+                            .map(|expires_at| expires_at.to_string())
+                            .unwrap_or_default(),
+                    );
+            }
+
+            let replaced: i64 = invocation
+                .invoke_async(&mut self.connection.clone())
+                .await
+                .map_err(storage_error)?;
+
+            return match replaced {
+                0 => Err(StorageError::NotFound),
+                -1 => Err(secret_conflict()),
+                -2 => continue,
+                _ => Ok(()),
+            };
+        }
+    }
+
+    async fn with_last_used(
+        &self,
+        owner: &Owner,
+        mut keys: Vec<ApiKey>,
+    ) -> Result<Vec<ApiKey>, StorageError> {
+        let last_used: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(self.last_used_key(owner))
+            .await
+            .map_err(storage_error)?;
+
+        for key in &mut keys {
+            key.last_used_at = last_used
+                .get(&format!("{}:at", key.id))
+                .and_then(|last_used_at| last_used_at.parse().ok())
+                .and_then(DateTime::from_timestamp_millis);
+            key.last_used_ip = last_used
+                .get(&format!("{}:ip", key.id))
+                .and_then(|last_used_ip| last_used_ip.parse().ok());
+        }
+        Ok(keys)
+    }
+}
+
+fn internal_error(e: impl ToString) -> StorageError {
+    StorageError::InternalError(e.to_string())
+}
+
+fn secret_conflict() -> StorageError {
+    StorageError::Conflict("another key has the same secret".to_string())
+}
+
+/// Tells apart the Redis errors that callers can act on, such as a dropped
+/// connection or a server that is still loading, from those that point at
+/// a bug.
+fn storage_error(e: RedisError) -> StorageError {
+    if e.is_timeout() {
+        StorageError::Timeout(e.to_string())
+    } else if e.is_io_error()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || matches!(
+            e.kind(),
+            ErrorKind::BusyLoadingError
+                | ErrorKind::TryAgain
+                | ErrorKind::ClusterDown
+                | ErrorKind::MasterDown
+        )
+    {
+        StorageError::unavailable(e)
+    } else {
+        internal_error(e)
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for RedisStorage {
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let secret_key = self.secret_key(&key.secret);
+        let key_id = key.id;
+        let key = Self::serialize(owner, key)?;
+
+        let created: i64 = self
+            .create_key_script
+            .key(self.keys_key(owner))
+            .key(self.expirations_key())
+            .key(secret_key)
+            .key(self.key_count_key())
+            .arg(key_id.to_string())
+            .arg(Self::expiration_member(owner, key_id))
+            .arg(key.value)
+            .arg(key.index_entry)
+            .arg(
+                key.expires_at
+                    .map(|expires_at| expires_at.to_string())
+                    .unwrap_or_default(),
+            )
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(storage_error)?;
+
+        match created {
+            0 => Err(secret_conflict()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        let values: Vec<String> = self
+            .connection
+            .clone()
+            .hvals(self.keys_key(owner))
+            .await
+            .map_err(storage_error)?;
+
+        let now = Utc::now();
+        let keys = values
+            .iter()
+            .map(|value| serde_json::from_str::<ApiKey>(value).map_err(internal_error))
+            .filter(|key| !key.as_ref().is_ok_and(|key| key.is_expired(now)))
+            .collect::<Result<Vec<ApiKey>, StorageError>>()?;
+        self.with_last_used(owner, keys).await
+    }
+
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError> {
+        self.replace_key(owner, key_id, None).await
+    }
+
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let key_id = key.id;
+        let key = Self::serialize(owner, key)?;
+        self.replace_key(owner, key_id, Some(key)).await
+    }
+
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        secret: &str,
+    ) -> Result<Option<ApiKey>, StorageError> {
+        Ok(self
+            .lookup_key_across_users(secret)
+            .await?
+            .filter(|(key_owner, _)| key_owner == owner)
+            .map(|(_, key)| key))
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        let value: Option<String> = self
+            .connection
+            .clone()
+            .get(self.secret_key(secret))
+            .await
+            .map_err(storage_error)?;
+
+        let Some(entry) = value
+            .map(|value| serde_json::from_str::<IndexEntry>(&value).map_err(internal_error))
+            .transpose()?
+            .filter(|entry| !entry.key.is_expired(Utc::now()))
+        else {
+            return Ok(None);
+        };
+
+        let owner = Owner {
+            kind: entry.owner_type,
+            id: entry.owner_id,
+        };
+        let key = self
+            .with_last_used(&owner, vec![entry.key])
+            .await?
+            .remove(0);
+        Ok(Some((owner, key)))
+    }
+
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError> {
+        let members: Vec<String> = self
+            .connection
+            .clone()
+            .zrangebyscore(self.expirations_key(), "-inf", now.timestamp_millis())
+            .await
+            .map_err(storage_error)?;
+
+        let mut purged = 0;
+        for member in members {
+            let Some((owner, key_id)) = Self::parse_expiration_member(&member) else {
+                continue;
+            };
+            let key_id = Uuid::parse_str(key_id).map_err(internal_error)?;
+            match self.replace_key(&owner, key_id, None).await {
+                Ok(()) => purged += 1,
+                Err(StorageError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError> {
+        for key_use in key_uses {
+            self.record_last_used_script
+                .key(self.keys_key(&key_use.owner))
+                .key(self.last_used_key(&key_use.owner))
+                .arg(key_use.key_id.to_string())
+                .arg(key_use.used_at.timestamp_millis())
+                .arg(
+                    key_use
+                        .client_ip
+                        .map(|client_ip| client_ip.to_string())
+                        .unwrap_or_default(),
+                )
+                .invoke_async::<i64>(&mut self.connection.clone())
+                .await
+                .map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        let count: Option<u64> = self
+            .connection
+            .clone()
+            .get(self.key_count_key())
+            .await
+            .map_err(storage_error)?;
+        Ok(count.unwrap_or_default())
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        let _: String = redis::cmd("PING")
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        let (allowed, tokens): (i64, String) = self
+            .consume_rate_limit_script
+            .key(self.rate_limit_key(key_id))
+            .arg(rate_limit.requests)
+            .arg(rate_limit.period_seconds * 1000)
+            .arg(now.timestamp_millis())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(storage_error)?;
+        let tokens = tokens.parse().map_err(internal_error)?;
+        Ok(RateLimitStatus::new(rate_limit, tokens, allowed == 1, now))
+    }
+
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        let (counted, used): (i64, u64) = self
+            .increment_usage_script
+            .key(self.usage_key(key_id))
+            .arg(now.format("%Y-%m").to_string())
+            .arg(now.format("%Y-%m-%d").to_string())
+            .arg(
+                monthly_quota
+                    .map(|quota| quota.to_string())
+                    .unwrap_or_default(),
+            )
+            .arg(USAGE_RETENTION.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(storage_error)?;
+        Ok((counted == 1, used))
+    }
+
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let _: i64 = self
+            .refund_usage_script
+            .key(self.usage_key(key_id))
+            .arg(now.format("%Y-%m").to_string())
+            .arg(now.format("%Y-%m-%d").to_string())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        let usage: HashMap<String, u64> = self
+            .connection
+            .clone()
+            .hgetall(self.usage_key(key_id))
+            .await
+            .map_err(storage_error)?;
+
+        let mut usage = usage
+            .into_iter()
+            .filter_map(|(date, requests)| {
+                let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+                (from <= date && date < until).then_some(DailyUsage { date, requests })
+            })
+            .collect::<Vec<DailyUsage>>();
+        usage.sort_by_key(|usage| usage.date);
+        Ok(usage)
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum_auth_provider::AuthProvider;
+
+use crate::{constant_time_eq, ServiceAuthenticator};
+
+/// Accepts a single static shared credential.
+pub struct StaticTokenAuthenticator {
+    token: String,
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new(token: impl Into<String>) -> Arc<Self> {
+        Arc::new(Self {
+            token: token.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceAuthenticator for StaticTokenAuthenticator {
+    async fn authenticate(&self, credential: &str) -> bool {
+        constant_time_eq(credential, &self.token)
+    }
+}
+
+/// Accepts any JWT the wrapped [`AuthProvider`] verifies, typically one
+/// configured for a service-only audience.
+pub struct AuthProviderAuthenticator {
+    auth_provider: Arc<dyn AuthProvider>,
+}
+
+impl AuthProviderAuthenticator {
+    pub fn new(auth_provider: Arc<dyn AuthProvider>) -> Arc<Self> {
+        Arc::new(Self { auth_provider })
+    }
+}
+
+#[async_trait]
+impl ServiceAuthenticator for AuthProviderAuthenticator {
+    async fn authenticate(&self, credential: &str) -> bool {
+        self.auth_provider.verify(credential).await.is_ok()
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::SecretHasher;
+
+/// Hashes secrets with HMAC-SHA256, keyed by a server-side pepper.
+pub struct Sha256SecretHasher {
+    pepper: Vec<u8>,
+}
+
+impl Sha256SecretHasher {
+    pub fn new(pepper: impl Into<Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self {
+            pepper: pepper.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl SecretHasher for Sha256SecretHasher {
+    async fn hash(&self, secret: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.pepper).expect("HMAC accepts keys of any length");
+        mac.update(secret.as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+}
@@ -0,0 +1,539 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{
+    calendar_month, ApiKey, DailyUsage, KeyUse, Owner, RateLimit, RateLimitStatus, StorageAdapter,
+    StorageError, TokenBucket,
+};
+
+/// Schema migrations, applied in order. The index of each entry plus one is
+/// the schema version recorded in SQLite's `user_version` pragma once the
+/// migration has run, so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE api_keys (
+        id TEXT PRIMARY KEY NOT NULL,
+        owner_type TEXT NOT NULL,
+        owner_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        secret TEXT NOT NULL
+    );
+    CREATE INDEX api_keys_owner_secret ON api_keys (owner_type, owner_id, secret);",
+    "CREATE INDEX api_keys_secret ON api_keys (secret);",
+    "ALTER TABLE api_keys ADD COLUMN expires_at INTEGER;
+    CREATE INDEX api_keys_expires_at ON api_keys (expires_at);",
+    "ALTER TABLE api_keys ADD COLUMN scopes TEXT NOT NULL DEFAULT '[]';",
+    "ALTER TABLE api_keys ADD COLUMN last_used_at INTEGER;
+    ALTER TABLE api_keys ADD COLUMN last_used_ip TEXT;",
+    "ALTER TABLE api_keys ADD COLUMN rate_limit_requests INTEGER;
+    ALTER TABLE api_keys ADD COLUMN rate_limit_period_seconds INTEGER;
+    CREATE TABLE rate_limit_buckets (
+        key_id TEXT PRIMARY KEY NOT NULL,
+        tokens REAL NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TRIGGER api_keys_delete_rate_limit_bucket AFTER DELETE ON api_keys
+    BEGIN
+        DELETE FROM rate_limit_buckets WHERE key_id = OLD.id;
+    END;",
+    "ALTER TABLE api_keys ADD COLUMN monthly_quota INTEGER;
+    CREATE TABLE key_usage (
+        key_id TEXT NOT NULL,
+        day TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        PRIMARY KEY (key_id, day)
+    );
+    CREATE TRIGGER api_keys_delete_key_usage AFTER DELETE ON api_keys
+    BEGIN
+        DELETE FROM key_usage WHERE key_id = OLD.id;
+    END;",
+];
+
+/// Columns read by [`api_key_from_row`], in order.
+const API_KEY_COLUMNS: &str = "id, name, secret, expires_at, scopes, last_used_at, \
+    last_used_ip, rate_limit_requests, rate_limit_period_seconds, monthly_quota";
+
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Arc<Self>, StorageError> {
+        Self::from_connection(Connection::open(path).map_err(storage_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<Arc<Self>, StorageError> {
+        Self::from_connection(Connection::open_in_memory().map_err(storage_error)?)
+    }
+
+    fn from_connection(mut connection: Connection) -> Result<Arc<Self>, StorageError> {
+        migrate(&mut connection).map_err(storage_error)?;
+        Ok(Arc::new(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        }))
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StorageError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|e| StorageError::InternalError(e.to_string()))?;
+            f(&mut connection)
+        })
+        .await
+        .map_err(|e| StorageError::InternalError(e.to_string()))?
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn internal_error(e: impl ToString) -> StorageError {
+    StorageError::InternalError(e.to_string())
+}
+
+/// Tells apart the SQLite errors that callers can act on, such as a busy
+/// database, from those that point at a bug.
+fn storage_error(e: rusqlite::Error) -> StorageError {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => StorageError::Conflict(e.to_string()),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+            StorageError::Timeout(e.to_string())
+        }
+        Some(
+            ErrorCode::CannotOpen
+            | ErrorCode::DiskFull
+            | ErrorCode::SystemIoFailure
+            | ErrorCode::ReadOnly,
+        ) => StorageError::unavailable(e),
+        Some(ErrorCode::TooBig) => StorageError::InvalidInput(e.to_string()),
+        _ => internal_error(e),
+    }
+}
+
+fn api_key_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    let id: String = row.get(0)?;
+    Ok(ApiKey {
+        id: Uuid::parse_str(&id).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+        })?,
+        name: row.get(1)?,
+        secret: row.get(2)?,
+        expires_at: row
+            .get::<_, Option<i64>>(3)?
+            .map(|expires_at| {
+                DateTime::from_timestamp_millis(expires_at)
+                    .ok_or(rusqlite::Error::IntegralValueOutOfRange(3, expires_at))
+            })
+            .transpose()?,
+        scopes: serde_json::from_str(&row.get::<_, String>(4)?).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, e.into())
+        })?,
+        last_used_at: row
+            .get::<_, Option<i64>>(5)?
+            .map(|last_used_at| {
+                DateTime::from_timestamp_millis(last_used_at)
+                    .ok_or(rusqlite::Error::IntegralValueOutOfRange(5, last_used_at))
+            })
+            .transpose()?,
+        last_used_ip: row
+            .get::<_, Option<String>>(6)?
+            .map(|last_used_ip| {
+                last_used_ip.parse().map_err(|e: std::net::AddrParseError| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        6,
+                        rusqlite::types::Type::Text,
+                        e.into(),
+                    )
+                })
+            })
+            .transpose()?,
+        rate_limit: match (row.get::<_, Option<u32>>(7)?, row.get::<_, Option<u64>>(8)?) {
+            (Some(requests), Some(period_seconds)) => Some(RateLimit {
+                requests,
+                period_seconds,
+            }),
+            _ => None,
+        },
+        monthly_quota: row.get(9)?,
+    })
+}
+
+fn owner_from_row(row: &rusqlite::Row, index: usize) -> rusqlite::Result<Owner> {
+    Ok(Owner {
+        kind: row.get::<_, String>(index)?.parse().map_err(|e: String| {
+            rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+        })?,
+        id: row.get(index + 1)?,
+    })
+}
+
+#[async_trait]
+impl StorageAdapter for SqliteStorage {
+    async fn create_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let owner = owner.clone();
+        self.call(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO api_keys (id, owner_type, owner_id, name, secret, expires_at, scopes,
+                    rate_limit_requests, rate_limit_period_seconds, monthly_quota)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    params![
+                        key.id.to_string(),
+                        owner.kind.as_str(),
+                        owner.id,
+                        key.name,
+                        key.secret,
+                        key.expires_at
+                            .map(|expires_at| expires_at.timestamp_millis()),
+                        serde_json::to_string(&key.scopes).map_err(internal_error)?,
+                        key.rate_limit.map(|rate_limit| rate_limit.requests),
+                        key.rate_limit.map(|rate_limit| rate_limit.period_seconds),
+                        key.monthly_quota,
+                    ],
+                )
+                .map_err(storage_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_keys(&self, owner: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        let owner = owner.clone();
+        self.call(move |connection| {
+            let mut statement = connection
+                .prepare(&format!(
+                    "SELECT {} FROM api_keys WHERE owner_type = ?1 AND owner_id = ?2
+                    AND (expires_at IS NULL OR expires_at > ?3)
+                    ORDER BY rowid",
+                    API_KEY_COLUMNS
+                ))
+                .map_err(storage_error)?;
+            let keys = statement
+                .query_map(
+                    params![owner.kind.as_str(), owner.id, Utc::now().timestamp_millis()],
+                    api_key_from_row,
+                )
+                .map_err(storage_error)?
+                .collect::<rusqlite::Result<Vec<ApiKey>>>()
+                .map_err(storage_error)?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn delete_key(&self, owner: &Owner, key_id: Uuid) -> Result<(), StorageError> {
+        let owner = owner.clone();
+        self.call(move |connection| {
+            match connection
+                .execute(
+                    "DELETE FROM api_keys WHERE owner_type = ?1 AND owner_id = ?2 AND id = ?3",
+                    params![owner.kind.as_str(), owner.id, key_id.to_string()],
+                )
+                .map_err(storage_error)?
+            {
+                0 => Err(StorageError::NotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn update_key(&self, owner: &Owner, key: ApiKey) -> Result<(), StorageError> {
+        let owner = owner.clone();
+        self.call(move |connection| {
+            match connection
+                .execute(
+                    "UPDATE api_keys SET name = ?1, secret = ?2, expires_at = ?3, scopes = ?4,
+                    rate_limit_requests = ?5, rate_limit_period_seconds = ?6,
+                    monthly_quota = ?7
+                    WHERE owner_type = ?8 AND owner_id = ?9 AND id = ?10",
+                    params![
+                        key.name,
+                        key.secret,
+                        key.expires_at
+                            .map(|expires_at| expires_at.timestamp_millis()),
+                        serde_json::to_string(&key.scopes).map_err(internal_error)?,
+                        key.rate_limit.map(|rate_limit| rate_limit.requests),
+                        key.rate_limit.map(|rate_limit| rate_limit.period_seconds),
+                        key.monthly_quota,
+                        owner.kind.as_str(),
+                        owner.id,
+                        key.id.to_string(),
+                    ],
+                )
+                .map_err(storage_error)?
+            {
+                0 => Err(StorageError::NotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn lookup_key(
+        &self,
+        owner: &Owner,
+        secret: &str,
+    ) -> Result<Option<ApiKey>, StorageError> {
+        let owner = owner.clone();
+        let secret = secret.to_string();
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM api_keys
+                        WHERE owner_type = ?1 AND owner_id = ?2 AND secret = ?3
+                        AND (expires_at IS NULL OR expires_at > ?4)",
+                        API_KEY_COLUMNS
+                    ),
+                    params![
+                        owner.kind.as_str(),
+                        owner.id,
+                        secret,
+                        Utc::now().timestamp_millis()
+                    ],
+                    api_key_from_row,
+                )
+                .optional()
+                .map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        secret: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        let secret = secret.to_string();
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {}, owner_type, owner_id FROM api_keys
+                        WHERE secret = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                        API_KEY_COLUMNS
+                    ),
+                    params![secret, Utc::now().timestamp_millis()],
+                    |row| Ok((owner_from_row(row, 10)?, api_key_from_row(row)?)),
+                )
+                .optional()
+                .map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn purge_expired_keys(&self, now: DateTime<Utc>) -> Result<u64, StorageError> {
+        self.call(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM api_keys WHERE expires_at <= ?1",
+                    params![now.timestamp_millis()],
+                )
+                .map(|purged| purged as u64)
+                .map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn record_last_used(&self, key_uses: &[KeyUse]) -> Result<(), StorageError> {
+        let key_uses = key_uses.to_vec();
+        self.call(move |connection| {
+            let transaction = connection.transaction().map_err(storage_error)?;
+            for key_use in key_uses {
+                transaction
+                    .execute(
+                        "UPDATE api_keys
+                        SET last_used_at = ?1, last_used_ip = COALESCE(?2, last_used_ip)
+                        WHERE owner_type = ?3 AND owner_id = ?4 AND id = ?5
+                        AND (last_used_at IS NULL OR last_used_at < ?1)",
+                        params![
+                            key_use.used_at.timestamp_millis(),
+                            key_use.client_ip.map(|client_ip| client_ip.to_string()),
+                            key_use.owner.kind.as_str(),
+                            key_use.owner.id,
+                            key_use.key_id.to_string(),
+                        ],
+                    )
+                    .map_err(storage_error)?;
+            }
+            transaction.commit().map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        self.call(move |connection| {
+            connection
+                .query_row("SELECT COUNT(*) FROM api_keys", [], |row| row.get(0))
+                .map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            connection
+                .query_row("SELECT 1", [], |_| Ok(()))
+                .map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        key_id: Uuid,
+        rate_limit: &RateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        let rate_limit = *rate_limit;
+        self.call(move |connection| {
+            let transaction = connection.transaction().map_err(storage_error)?;
+            let bucket = transaction
+                .query_row(
+                    "SELECT tokens, updated_at FROM rate_limit_buckets WHERE key_id = ?1",
+                    params![key_id.to_string()],
+                    |row| Ok((row.get::<_, f64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()
+                .map_err(storage_error)?
+                .and_then(|(tokens, updated_at)| {
+                    Some(TokenBucket {
+                        tokens,
+                        updated_at: DateTime::from_timestamp_millis(updated_at)?,
+                    })
+                });
+
+            let (bucket, status) = TokenBucket::take(bucket, &rate_limit, now);
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO rate_limit_buckets (key_id, tokens, updated_at)
+                    VALUES (?1, ?2, ?3)",
+                    params![
+                        key_id.to_string(),
+                        bucket.tokens,
+                        bucket.updated_at.timestamp_millis()
+                    ],
+                )
+                .map_err(storage_error)?;
+            transaction.commit().map_err(storage_error)?;
+            Ok(status)
+        })
+        .await
+    }
+
+    async fn increment_usage(
+        &self,
+        key_id: Uuid,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        self.call(move |connection| {
+            let (from, until) = calendar_month(now.date_naive());
+            let transaction = connection.transaction().map_err(storage_error)?;
+            let used: u64 = transaction
+                .query_row(
+                    "SELECT COALESCE(SUM(requests), 0) FROM key_usage
+                    WHERE key_id = ?1 AND day >= ?2 AND day < ?3",
+                    params![key_id.to_string(), from.to_string(), until.to_string()],
+                    |row| row.get(0),
+                )
+                .map_err(storage_error)?;
+            if monthly_quota.is_some_and(|quota| used >= quota) {
+                return Ok((false, used));
+            }
+
+            transaction
+                .execute(
+                    "INSERT INTO key_usage (key_id, day, requests) VALUES (?1, ?2, 1)
+                    ON CONFLICT (key_id, day) DO UPDATE SET requests = requests + 1",
+                    params![key_id.to_string(), now.date_naive().to_string()],
+                )
+                .map_err(storage_error)?;
+            transaction.commit().map_err(storage_error)?;
+            Ok((true, used + 1))
+        })
+        .await
+    }
+
+    async fn refund_usage(&self, key_id: Uuid, now: DateTime<Utc>) -> Result<(), StorageError> {
+        self.call(move |connection| {
+            let transaction = connection.transaction().map_err(storage_error)?;
+            transaction
+                .execute(
+                    "UPDATE key_usage SET requests = requests - 1
+                    WHERE key_id = ?1 AND day = ?2 AND requests > 0",
+                    params![key_id.to_string(), now.date_naive().to_string()],
+                )
+                .map_err(storage_error)?;
+            transaction
+                .execute(
+                    "DELETE FROM key_usage WHERE key_id = ?1 AND day = ?2 AND requests = 0",
+                    params![key_id.to_string(), now.date_naive().to_string()],
+                )
+                .map_err(storage_error)?;
+            transaction.commit().map_err(storage_error)
+        })
+        .await
+    }
+
+    async fn get_usage(
+        &self,
+        key_id: Uuid,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        self.call(move |connection| {
+            let mut statement = connection
+                .prepare(
+                    "SELECT day, requests FROM key_usage
+                    WHERE key_id = ?1 AND day >= ?2 AND day < ?3 ORDER BY day",
+                )
+                .map_err(storage_error)?;
+            let usage = statement
+                .query_map(
+                    params![key_id.to_string(), from.to_string(), until.to_string()],
+                    |row| {
+                        Ok(DailyUsage {
+                            date: row.get::<_, String>(0)?.parse().map_err(
+                                |e: chrono::ParseError| {
+                                    rusqlite::Error::FromSqlConversionFailure(
+                                        0,
+                                        rusqlite::types::Type::Text,
+                                        e.into(),
+                                    )
+                                },
+                            )?,
+                            requests: row.get(1)?,
+                        })
+                    },
+                )
+                .map_err(storage_error)?
+                .collect::<rusqlite::Result<Vec<DailyUsage>>>()
+                .map_err(storage_error)?;
+            Ok(usage)
+        })
+        .await
+    }
+}
@@ -0,0 +1,32 @@
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+
+/// Builds a provider that batches spans and exports them as OTLP/HTTP
+/// protobuf to `<endpoint>/v1/traces`, e.g. `http://localhost:4318` for a
+/// local collector. Batches are sent from a Tokio task, so the provider
+/// must be built inside a runtime.
+pub fn otlp_tracer_provider(endpoint: &str, service_name: &str) -> Result<TracerProvider, String> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|e| format!("Failed to build OTLP exporter: {}", e))?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build())
+}
+
+/// Exports the spans still buffered by `provider` and stops it.
+pub async fn shutdown(provider: TracerProvider) -> Result<(), String> {
+    // Shutting down blocks until the export task has flushed, which must
+    // not happen on a runtime thread.
+    tokio::task::spawn_blocking(move || provider.shutdown())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to export spans: {}", e))
+}
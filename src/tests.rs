@@ -0,0 +1,3593 @@
+use axum::async_trait;
+use axum_auth_provider::{AuthError, Claims};
+use axum_test::{TestResponse, TestServer};
+use in_memory_storage::InMemoryStorage;
+use jsonwebtoken::{jwk::JwkSet, TokenData};
+use organization_directory::{
+    ClaimOrganizationDirectory, OrganizationMember, StaticOrganizationDirectory,
+};
+use owner_resolver::{ClaimOwnerResolver, TemplateOwnerResolver};
+use service_authenticator::StaticTokenAuthenticator;
+use uuid_secret_generator::UuidSecretGenerator;
+
+use super::*;
+
+struct TestAuthProvider;
+
+impl TestAuthProvider {
+    fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for TestAuthProvider {
+    /// The key that test JWTs are signed with.
+    async fn jwk_set(&self) -> Result<JwkSet, AuthError> {
+        Ok(serde_json::from_value(
+            serde_json::json!({ "keys": [{ "kty": "oct", "k": "dGVzdA==" }] }),
+        )
+        .unwrap())
+    }
+
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, AuthError> {
+        Ok(TokenData {
+            header: Default::default(),
+            claims: Claims {
+                sub: token.to_string(),
+                exp: 0,
+            },
+        })
+    }
+}
+
+struct TestClient {
+    server: TestServer,
+    storage_adapter: Arc<dyn StorageAdapter>,
+    last_used_recorder: Arc<LastUsedRecorder>,
+}
+
+impl TestClient {
+    fn new(storage_adapter: Arc<dyn StorageAdapter>) -> Self {
+        Self::with_builder(
+            ApiKeyServer::builder().with_organization_directory(StaticOrganizationDirectory::new(
+                [
+                    OrganizationMember {
+                        user_id: "org_admin_token".to_string(),
+                        organization_id: "acme".to_string(),
+                        role: OrganizationRole::Admin,
+                    },
+                    OrganizationMember {
+                        user_id: "org_member_token".to_string(),
+                        organization_id: "acme".to_string(),
+                        role: OrganizationRole::Member,
+                    },
+                ],
+            )),
+            storage_adapter,
+        )
+    }
+
+    fn with_builder(
+        builder: ApiKeyServerBuilder,
+        storage_adapter: Arc<dyn StorageAdapter>,
+    ) -> Self {
+        let api_key_server = builder
+            .with_storage_adapter(storage_adapter.clone())
+            .with_auth_provider(TestAuthProvider::new())
+            .with_secret_generator(UuidSecretGenerator::new())
+            .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+            .with_allowed_scopes(["orders:read", "orders:write"])
+            .with_service_authenticator(StaticTokenAuthenticator::new("service_token"))
+            .build()
+            .unwrap();
+        let last_used_recorder = api_key_server.last_used_recorder.clone();
+        Self {
+            server: TestServer::new(api_key_server.router()).unwrap(),
+            storage_adapter,
+            last_used_recorder,
+        }
+    }
+
+    async fn create_key(&self, key: InputApiKey, token: &str) -> TestResponse {
+        self.server
+            .post("/keys")
+            .json(&serde_json::to_value(key).unwrap())
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn list_keys(&self, token: &str) -> TestResponse {
+        self.server
+            .get("/keys")
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn delete_key(&self, id: Uuid, token: &str) -> TestResponse {
+        self.server
+            .delete(&format!("/keys/{}", id))
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn regenerate_key(&self, id: Uuid, token: &str) -> TestResponse {
+        self.server
+            .post(&format!("/keys/{}", id))
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn lookup_key(&self, secret: String, token: &str) -> TestResponse {
+        self.server
+            .post("/lookup")
+            .json(&LookupSecret {
+                secret,
+                client_ip: None,
+            })
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn verify_key(&self, secret: String, credential: &str) -> TestResponse {
+        self.server
+            .post("/verify")
+            .json(&LookupSecret {
+                secret,
+                client_ip: None,
+            })
+            .add_header("Authorization", &format!("Bearer {}", credential))
+            .await
+    }
+
+    async fn get_usage(&self, id: Uuid, month: Option<&str>, token: &str) -> TestResponse {
+        let mut request = self.server.get(&format!("/keys/{}/usage", id));
+        if let Some(month) = month {
+            request = request.add_query_param("month", month);
+        }
+        request
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await
+    }
+
+    async fn healthz(&self) -> TestResponse {
+        self.server.get("/healthz").await
+    }
+
+    async fn flush_last_used(&self) {
+        self.last_used_recorder
+            .flush(self.storage_adapter.as_ref())
+            .await
+            .unwrap();
+    }
+}
+
+macro_rules! storage_adapter_tests {
+    ($(#[$attr:meta])* $module:ident, $storage_adapter:expr) => {
+        storage_adapter_tests!(
+            [$(#[$attr])*]
+            $module,
+            $storage_adapter,
+            [
+                test_create_key,
+                test_list_keys,
+                test_delete_key,
+                test_regenerate_key,
+                test_successful_lookup_key,
+                test_invalid_lookup_key,
+                test_different_tokens_access_different_keys,
+                test_verify_key,
+                test_expired_keys,
+                test_key_scopes,
+                test_last_used,
+                test_count_keys,
+                test_rate_limit,
+                test_usage_quota,
+                test_organization_keys,
+            ]
+        );
+    };
+    ($attrs:tt $module:ident, $storage_adapter:expr, [$($test:ident),* $(,)?]) => {
+        mod $module {
+            use super::*;
+
+            $(storage_adapter_tests!(@test $attrs $test, $storage_adapter);)*
+        }
+    };
+    (@test [$(#[$attr:meta])*] $test:ident, $storage_adapter:expr) => {
+        #[tokio::test]
+        $(#[$attr])*
+        async fn $test() {
+            super::$test(TestClient::new($storage_adapter)).await;
+        }
+    };
+}
+
+storage_adapter_tests!(in_memory_storage_tests, InMemoryStorage::new());
+
+#[cfg(feature = "sqlite")]
+storage_adapter_tests!(
+    sqlite_storage_tests,
+    sqlite_storage::SqliteStorage::open_in_memory().unwrap()
+);
+
+#[cfg(feature = "postgres")]
+storage_adapter_tests!(
+    #[ignore = "requires a local Postgres instance, see POSTGRES_URL"]
+    postgres_storage_tests,
+    postgres_test_storage().await
+);
+
+#[cfg(feature = "redis")]
+storage_adapter_tests!(
+    #[ignore = "requires a local Redis instance, see REDIS_URL"]
+    redis_storage_tests,
+    redis_test_storage().await
+);
+
+/// Connects to the Postgres instance at `POSTGRES_URL` (defaulting to a
+/// local server) inside a fresh schema, so tests never see each other's keys.
+#[cfg(feature = "postgres")]
+async fn postgres_test_storage() -> Arc<dyn StorageAdapter> {
+    let url = std::env::var("POSTGRES_URL")
+        .unwrap_or_else(|_| "postgres://postgres@localhost:5432/postgres".to_string());
+    let schema = format!("test_{}", Uuid::new_v4().simple());
+
+    let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    client
+        .batch_execute(&format!("CREATE SCHEMA {}", schema))
+        .await
+        .unwrap();
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    postgres_storage::PostgresStorage::connect(
+        &format!("{}{}options=-csearch_path%3D{}", url, separator, schema),
+        4,
+    )
+    .await
+    .unwrap()
+}
+
+/// Connects to the Redis instance at `REDIS_URL` (defaulting to a local
+/// server) under a fresh namespace, so tests never see each other's keys.
+#[cfg(feature = "redis")]
+async fn redis_test_storage() -> Arc<dyn StorageAdapter> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
+
+    redis_storage::RedisStorage::connect(&url, format!("test_{}", Uuid::new_v4().simple()))
+        .await
+        .unwrap()
+}
+
+async fn test_create_key(client: TestClient) {
+    let api_key_name = String::from("my api key");
+    let response = client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+
+    assert_eq!(response.status_code(), 200);
+    assert!(!response.json::<ApiKey>().id.to_string().is_empty());
+    assert_eq!(response.json::<ApiKey>().name, api_key_name.clone());
+    assert!(!response.json::<ApiKey>().secret.is_empty());
+}
+
+async fn test_list_keys(client: TestClient) {
+    let api_key_name = String::from("my api key 1");
+    client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+
+    let api_key_name = String::from("my api key 2");
+    client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+
+    let response = client.list_keys("test_token").await;
+
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.json::<Vec<ProtectedApiKey>>().len(), 2);
+}
+
+async fn test_delete_key(client: TestClient) {
+    let api_key_name = String::from("my api key");
+    let response = client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    let id = response.json::<ApiKey>().id;
+
+    let delete_response = client.delete_key(id, "test_token").await;
+    assert_eq!(delete_response.status_code(), 204);
+
+    let list_response = client.list_keys("test_token").await;
+    assert_eq!(list_response.json::<Vec<ProtectedApiKey>>().len(), 0);
+}
+
+async fn test_regenerate_key(client: TestClient) {
+    let api_key_name = String::from("my api key");
+    let create_response = client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    let original_key = create_response.json::<ApiKey>();
+
+    let regenerate_response = client.regenerate_key(original_key.id, "test_token").await;
+    assert_eq!(regenerate_response.status_code(), 200);
+
+    let regenerated_key = regenerate_response.json::<ApiKey>();
+    assert_eq!(regenerated_key.id, original_key.id);
+    assert_eq!(regenerated_key.name, original_key.name);
+    assert_ne!(regenerated_key.secret, original_key.secret);
+}
+
+async fn test_successful_lookup_key(client: TestClient) {
+    let api_key_name = String::from("my api key");
+    let create_response = client
+        .create_key(
+            InputApiKey {
+                name: api_key_name.clone(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    let created_key = create_response.json::<ApiKey>();
+
+    let lookup_response = client.lookup_key(created_key.secret, "test_token").await;
+    assert_eq!(lookup_response.status_code(), 200);
+
+    let looked_up_key = lookup_response.json::<ProtectedApiKey>();
+    assert_eq!(looked_up_key.id, created_key.id);
+    assert_eq!(looked_up_key.name, created_key.name);
+}
+
+async fn test_invalid_lookup_key(client: TestClient) {
+    let invalid_lookup_response = client
+        .lookup_key("invalid_secret".to_string(), "test_token")
+        .await;
+    assert_eq!(invalid_lookup_response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_healthz() {
+    let client = TestClient::new(InMemoryStorage::new());
+    let response = client.healthz().await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let directory = tempfile::tempdir().unwrap();
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_audit_sink(audit_sink::JsonLinesAuditSink::new(
+            directory.path().join("audit.jsonl"),
+        )),
+        InMemoryStorage::new(),
+    );
+
+    let created_key = client
+        .server
+        .post("/keys")
+        .json(&InputApiKey {
+            name: "my api key".to_string(),
+            ..Default::default()
+        })
+        .add_header("Authorization", "Bearer test_token")
+        .add_header("x-request-id", "create-request")
+        .await
+        .json::<ApiKey>();
+    let regenerated_key = client
+        .regenerate_key(created_key.id, "test_token")
+        .await
+        .json::<ApiKey>();
+    client.lookup_key(created_key.secret, "test_token").await;
+    client
+        .lookup_key(regenerated_key.secret, "test_token")
+        .await;
+    client.delete_key(created_key.id, "test_token").await;
+    client.delete_key(created_key.id, "other_token").await;
+    client.list_keys("test_token").await;
+
+    let events = client
+        .server
+        .get("/audit")
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .json::<Vec<AuditEvent>>();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.action, event.key_id, event.outcome))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                AuditAction::Create,
+                Some(created_key.id),
+                AuditOutcome::Success
+            ),
+            (
+                AuditAction::Regenerate,
+                Some(created_key.id),
+                AuditOutcome::Success
+            ),
+            (AuditAction::Lookup, None, AuditOutcome::NotFound),
+            (
+                AuditAction::Lookup,
+                Some(created_key.id),
+                AuditOutcome::Success
+            ),
+            (
+                AuditAction::Delete,
+                Some(created_key.id),
+                AuditOutcome::Success
+            ),
+        ]
+    );
+    assert!(events.iter().all(|event| event.actor == "test_token"));
+    assert_eq!(events[0].request_id, "create-request");
+    assert!(!events[1].request_id.is_empty());
+
+    let events = client
+        .server
+        .get("/audit")
+        .add_header("Authorization", "Bearer other_token")
+        .await
+        .json::<Vec<AuditEvent>>();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].outcome, AuditOutcome::NotFound);
+
+    let events = client
+        .server
+        .get("/audit")
+        .add_query_param("limit", 2)
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .json::<Vec<AuditEvent>>();
+    assert_eq!(
+        events.iter().map(|event| event.action).collect::<Vec<_>>(),
+        vec![AuditAction::Lookup, AuditAction::Delete]
+    );
+}
+
+#[tokio::test]
+async fn test_audit_disabled_without_audit_sink() {
+    let server = TestServer::new(router_with_secret_hasher(
+        TestAuthProvider::new(),
+        InMemoryStorage::new(),
+        UuidSecretGenerator::new(),
+        Sha256SecretHasher::new("test_pepper"),
+    ))
+    .unwrap();
+
+    let response = server
+        .get("/audit")
+        .add_header("Authorization", "Bearer test_token")
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_webhooks() {
+    use axum::http::HeaderMap;
+    use webhooks::{
+        sign, DeadLetter, RetryPolicy, WebhookDispatcher, WebhookSubscription, ID_HEADER,
+        SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+
+    type Deliveries = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    // Accepts deliveries on `/hook` after failing the first one, and
+    // rejects every delivery on `/broken`.
+    let deliveries = Deliveries::default();
+    let receiver = Router::new()
+        .route(
+            "/hook",
+            post(
+                |State(deliveries): State<Deliveries>,
+                 headers: HeaderMap,
+                 body: String| async move {
+                    let mut deliveries = deliveries.lock().await;
+                    deliveries.push((headers, body));
+                    match deliveries.len() {
+                        1 => StatusCode::SERVICE_UNAVAILABLE,
+                        _ => StatusCode::OK,
+                    }
+                },
+            ),
+        )
+        .route("/broken", post(|| async { StatusCode::GONE }))
+        .with_state(deliveries.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+    let directory = tempfile::tempdir().unwrap();
+    let dead_letter_path = directory.path().join("dead_letters.jsonl");
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_webhook_dispatcher(WebhookDispatcher::new(
+            vec![
+                WebhookSubscription {
+                    url: format!("http://{}/hook", address),
+                    secret: "webhook_secret".to_string(),
+                },
+                WebhookSubscription {
+                    url: format!("http://{}/broken", address),
+                    secret: "webhook_secret".to_string(),
+                },
+            ],
+            RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(20),
+            },
+            &dead_letter_path,
+            directory.path().join("outbox.jsonl"),
+        )),
+        InMemoryStorage::new(),
+    );
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    client.regenerate_key(created_key.id, "test_token").await;
+    client.delete_key(created_key.id, "test_token").await;
+    client.list_keys("test_token").await;
+
+    let dead_letters = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let dead_letters = tokio::fs::read_to_string(&dead_letter_path)
+                .await
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str::<DeadLetter>(line).unwrap())
+                .collect::<Vec<_>>();
+            if dead_letters.len() == 3 && deliveries.lock().await.len() == 4 {
+                return dead_letters;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let deliveries = deliveries.lock().await;
+    let mut event_types = Vec::new();
+    for (headers, body) in deliveries.iter() {
+        let header = |name: &str| headers.get(name).unwrap().to_str().unwrap();
+        let event = serde_json::from_str::<webhooks::WebhookEvent>(body).unwrap();
+        assert_eq!(header(ID_HEADER), event.id.to_string());
+        assert_eq!(
+            header(SIGNATURE_HEADER),
+            sign(
+                "webhook_secret",
+                event.id,
+                header(TIMESTAMP_HEADER).parse().unwrap(),
+                body
+            )
+        );
+        assert_eq!(event.owner, Owner::user("test_token"));
+        assert_eq!(event.key_id, created_key.id);
+        assert_eq!(
+            event.key.is_none(),
+            event.event_type == WebhookEventType::KeyDeleted
+        );
+        event_types.push(event.event_type);
+    }
+    // The first delivery failed and was retried.
+    assert_eq!(deliveries[0].1, deliveries[3].1);
+    event_types.sort_by_key(|event_type| *event_type as u8);
+    event_types.dedup();
+    assert_eq!(
+        event_types,
+        vec![
+            WebhookEventType::KeyCreated,
+            WebhookEventType::KeyRegenerated,
+            WebhookEventType::KeyDeleted,
+        ]
+    );
+
+    for dead_letter in dead_letters {
+        assert_eq!(dead_letter.url, format!("http://{}/broken", address));
+        assert_eq!(dead_letter.attempts, 3);
+        assert!(dead_letter.error.contains("410"));
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_outbox() {
+    use webhooks::{DeadLetter, RetryPolicy, WebhookDispatcher, WebhookEvent, WebhookSubscription};
+
+    let deliveries = Arc::new(Mutex::new(Vec::<String>::new()));
+    let receiver = Router::new()
+        .route(
+            "/hook",
+            post(
+                |State(deliveries): State<Arc<Mutex<Vec<String>>>>, body: String| async move {
+                    deliveries.lock().await.push(body);
+                    StatusCode::OK
+                },
+            ),
+        )
+        .route("/broken", post(|| async { StatusCode::GONE }))
+        .with_state(deliveries.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+    let directory = tempfile::tempdir().unwrap();
+    let dead_letter_path = directory.path().join("dead_letters.jsonl");
+    let outbox_path = directory.path().join("outbox.jsonl");
+    let dispatcher = |path: &str| {
+        WebhookDispatcher::new(
+            vec![WebhookSubscription {
+                url: format!("http://{}{}", address, path),
+                secret: "webhook_secret".to_string(),
+            }],
+            RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+            },
+            &dead_letter_path,
+            &outbox_path,
+        )
+    };
+    let event = || {
+        WebhookEvent::new(
+            WebhookEventType::KeyCreated,
+            Owner::user("test_token"),
+            Uuid::new_v4(),
+            None,
+        )
+    };
+    let dead_letters = || {
+        std::fs::read_to_string(&dead_letter_path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<DeadLetter>(line).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // Deliveries left pending by a crash are restarted, unless they were
+    // settled or their URL is no longer subscribed.
+    let (pending_event, settled_event, unsubscribed_event) = (event(), event(), event());
+    let settled_id = Uuid::new_v4();
+    let outbox = [
+        serde_json::json!({
+            "state": "pending",
+            "delivery_id": Uuid::new_v4(),
+            "url": format!("http://{}/hook", address),
+            "event": pending_event,
+        }),
+        serde_json::json!({
+            "state": "pending",
+            "delivery_id": settled_id,
+            "url": format!("http://{}/hook", address),
+            "event": settled_event,
+        }),
+        serde_json::json!({
+            "state": "pending",
+            "delivery_id": Uuid::new_v4(),
+            "url": format!("http://{}/old", address),
+            "event": unsubscribed_event,
+        }),
+        serde_json::json!({ "state": "settled", "delivery_id": settled_id }),
+    ]
+    .iter()
+    .map(|entry| entry.to_string() + "\n")
+    .collect::<String>();
+    std::fs::write(&outbox_path, outbox + "{\"state\":").unwrap();
+
+    let webhook_dispatcher = dispatcher("/hook");
+    assert_eq!(webhook_dispatcher.resume().await.unwrap(), 1);
+    webhook_dispatcher.shutdown(Duration::from_secs(5)).await;
+    let delivered = deliveries.lock().await.clone();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(
+        serde_json::from_str::<WebhookEvent>(&delivered[0])
+            .unwrap()
+            .id,
+        pending_event.id
+    );
+    let dead_letter = dead_letters().pop().unwrap();
+    assert_eq!(dead_letter.event.id, unsubscribed_event.id);
+    assert_eq!(dead_letter.error, "No longer subscribed");
+    assert_eq!(std::fs::read_to_string(&outbox_path).unwrap(), "");
+
+    // Deliveries still being retried at shutdown are dead-lettered.
+    let webhook_dispatcher = dispatcher("/broken");
+    let retried_event = event();
+    webhook_dispatcher.dispatch(retried_event.clone()).await;
+    assert!(std::fs::read_to_string(&outbox_path)
+        .unwrap()
+        .contains(&retried_event.id.to_string()));
+    webhook_dispatcher
+        .shutdown(Duration::from_millis(100))
+        .await;
+    let dead_letter = dead_letters().pop().unwrap();
+    assert_eq!(dead_letter.event.id, retried_event.id);
+    assert_eq!(dead_letter.attempts, 1);
+    assert!(dead_letter.error.contains("410"));
+    assert_eq!(std::fs::read_to_string(&outbox_path).unwrap(), "");
+    assert_eq!(webhook_dispatcher.resume().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let client = TestClient::new(InMemoryStorage::new());
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    client.lookup_key(created_key.secret, "test_token").await;
+    client
+        .lookup_key(Uuid::new_v4().to_string(), "test_token")
+        .await;
+    client.server.get("/keys").await;
+
+    let response = client.server.get("/metrics").await;
+    assert_eq!(response.status_code(), 200);
+    let metrics = response.text();
+    for line in [
+        r#"api_key_server_http_requests_total{route="create_key",status="200"} 1"#,
+        r#"api_key_server_http_requests_total{route="lookup_key",status="200"} 1"#,
+        r#"api_key_server_http_requests_total{route="lookup_key",status="404"} 1"#,
+        r#"api_key_server_http_requests_total{route="list_keys",status="401"} 1"#,
+        r#"api_key_server_http_request_duration_seconds_count{route="lookup_key"} 2"#,
+        r#"api_key_server_lookups_total{result="hit",route="lookup_key"} 1"#,
+        r#"api_key_server_lookups_total{result="miss",route="lookup_key"} 1"#,
+        r#"api_key_server_storage_operation_duration_seconds_count{operation="lookup_key"} 2"#,
+        "api_key_server_keys 1",
+    ] {
+        assert!(
+            metrics.contains(line),
+            "{} missing from:\n{}",
+            line,
+            metrics
+        );
+    }
+
+    client.delete_key(Uuid::new_v4(), "test_token").await;
+    let metrics = client.server.get("/metrics").await.text();
+    assert!(metrics.contains(
+        r#"api_key_server_storage_errors_total{error="not_found",operation="delete_key"} 1"#
+    ));
+}
+
+async fn test_count_keys(client: TestClient) {
+    let mut created_keys = Vec::new();
+    for token in ["user1_token", "user1_token", "user2_token"] {
+        let response = client
+            .create_key(
+                InputApiKey {
+                    name: "my api key".to_string(),
+                    ..Default::default()
+                },
+                token,
+            )
+            .await;
+        assert_eq!(response.status_code(), 200);
+        created_keys.push((response.json::<ApiKey>().id, token));
+    }
+    assert_eq!(client.storage_adapter.count_keys().await.unwrap(), 3);
+
+    let (id, token) = created_keys[0];
+    client.regenerate_key(id, token).await;
+    assert_eq!(client.storage_adapter.count_keys().await.unwrap(), 3);
+
+    for (id, token) in created_keys {
+        assert_eq!(client.delete_key(id, token).await.status_code(), 204);
+    }
+    assert_eq!(client.storage_adapter.count_keys().await.unwrap(), 0);
+}
+
+async fn test_different_tokens_access_different_keys(client: TestClient) {
+    let user1_key = client
+        .create_key(
+            InputApiKey {
+                name: "user1_key".to_string(),
+                ..Default::default()
+            },
+            "user1_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let user2_key = client
+        .create_key(
+            InputApiKey {
+                name: "user2_key".to_string(),
+                ..Default::default()
+            },
+            "user2_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let user1_keys = client
+        .list_keys("user1_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(user1_keys.len(), 1);
+    assert_eq!(user1_keys[0].id, user1_key.id);
+
+    let user2_keys = client
+        .list_keys("user2_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(user2_keys.len(), 1);
+    assert_eq!(user2_keys[0].id, user2_key.id);
+
+    let delete_response = client.delete_key(user2_key.id, "user1_token").await;
+    assert_eq!(delete_response.status_code(), 404);
+
+    let regenerate_response = client.regenerate_key(user1_key.id, "user2_token").await;
+    assert_eq!(regenerate_response.status_code(), 404);
+
+    let lookup_response = client.lookup_key(user2_key.secret, "user1_token").await;
+    assert_eq!(lookup_response.status_code(), 404);
+}
+
+async fn test_verify_key(client: TestClient) {
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "user1_key".to_string(),
+                ..Default::default()
+            },
+            "user1_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let verify_response = client
+        .verify_key(created_key.secret.clone(), "service_token")
+        .await;
+    assert_eq!(verify_response.status_code(), 200);
+
+    let verified_key = verify_response.json::<VerifiedApiKey>();
+    assert_eq!(verified_key.owner, Owner::user("user1_token"));
+    assert_eq!(verified_key.key.id, created_key.id);
+    assert_eq!(verified_key.key.name, created_key.name);
+
+    let invalid_verify_response = client
+        .verify_key("invalid_secret".to_string(), "service_token")
+        .await;
+    assert_eq!(invalid_verify_response.status_code(), 404);
+}
+
+async fn test_expired_keys(client: TestClient) {
+    let now = Utc::now();
+    let expired_key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "expired key".to_string(),
+        secret: "expired secret".to_string(),
+        expires_at: Some(now - chrono::Duration::hours(1)),
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: None,
+        monthly_quota: None,
+    };
+    let expiring_key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "expiring key".to_string(),
+        secret: "expiring secret".to_string(),
+        expires_at: Some(now + chrono::Duration::hours(1)),
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: None,
+        monthly_quota: None,
+    };
+    for key in [expired_key.clone(), expiring_key.clone()] {
+        client
+            .storage_adapter
+            .create_key(&Owner::user("test_token"), key)
+            .await
+            .unwrap();
+    }
+
+    let storage_adapter = &client.storage_adapter;
+    let keys = storage_adapter
+        .list_keys(&Owner::user("test_token"))
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].id, expiring_key.id);
+    assert!(storage_adapter
+        .lookup_key(&Owner::user("test_token"), "expired secret")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(storage_adapter
+        .lookup_key_across_users("expired secret")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        storage_adapter
+            .lookup_key(&Owner::user("test_token"), "expiring secret")
+            .await
+            .unwrap()
+            .and_then(|key| key.expires_at)
+            .map(|expires_at| expires_at.timestamp_millis()),
+        expiring_key
+            .expires_at
+            .map(|expires_at| expires_at.timestamp_millis())
+    );
+
+    storage_adapter
+        .increment_usage(expired_key.id, None, now)
+        .await
+        .unwrap();
+    assert_eq!(storage_adapter.purge_expired_keys(now).await.unwrap(), 1);
+    let (from, until) = calendar_month(now.date_naive());
+    assert!(storage_adapter
+        .get_usage(expired_key.id, from, until)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let keys = storage_adapter
+        .list_keys(&Owner::user("test_token"))
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].id, expiring_key.id);
+}
+
+async fn test_key_scopes(client: TestClient) {
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                scopes: vec!["orders:write".to_string(), "orders:read".to_string()],
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    let scopes = vec!["orders:read".to_string(), "orders:write".to_string()];
+    assert_eq!(created_key.scopes, scopes);
+
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys[0].scopes, scopes);
+
+    let regenerated_key = client
+        .regenerate_key(created_key.id, "test_token")
+        .await
+        .json::<ApiKey>();
+    assert_eq!(regenerated_key.scopes, scopes);
+
+    let looked_up_key = client
+        .lookup_key(regenerated_key.secret.clone(), "test_token")
+        .await
+        .json::<ProtectedApiKey>();
+    assert_eq!(looked_up_key.scopes, scopes);
+
+    let verified_key = client
+        .verify_key(regenerated_key.secret, "service_token")
+        .await
+        .json::<VerifiedApiKey>();
+    assert_eq!(verified_key.key.scopes, scopes);
+}
+
+async fn test_last_used(client: TestClient) {
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    assert_eq!(created_key.last_used_at, None);
+
+    let client_ip: IpAddr = "203.0.113.7".parse().unwrap();
+    let response = client
+        .server
+        .post("/lookup")
+        .json(&LookupSecret {
+            secret: created_key.secret.clone(),
+            client_ip: Some(client_ip),
+        })
+        .add_header("Authorization", "Bearer test_token")
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys[0].last_used_at, None);
+
+    client.flush_last_used().await;
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    let last_used_at = listed_keys[0].last_used_at.unwrap();
+    assert_eq!(listed_keys[0].last_used_ip, Some(client_ip));
+
+    client
+        .verify_key(created_key.secret.clone(), "service_token")
+        .await;
+    client.flush_last_used().await;
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert!(listed_keys[0].last_used_at.unwrap() >= last_used_at);
+    assert_eq!(listed_keys[0].last_used_ip, Some(client_ip));
+
+    client
+        .storage_adapter
+        .record_last_used(&[
+            KeyUse {
+                owner: Owner::user("test_token"),
+                key_id: created_key.id,
+                used_at: last_used_at - chrono::Duration::hours(1),
+                client_ip: Some("198.51.100.1".parse().unwrap()),
+            },
+            KeyUse {
+                owner: Owner::user("other_token"),
+                key_id: created_key.id,
+                used_at: Utc::now() + chrono::Duration::hours(1),
+                client_ip: None,
+            },
+        ])
+        .await
+        .unwrap();
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert!(listed_keys[0].last_used_at.unwrap() >= last_used_at);
+    assert!(listed_keys[0].last_used_at.unwrap() < Utc::now());
+    assert_eq!(listed_keys[0].last_used_ip, Some(client_ip));
+}
+
+async fn test_rate_limit(client: TestClient) {
+    let rate_limit = RateLimit {
+        requests: 2,
+        period_seconds: 60,
+    };
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                rate_limit: Some(rate_limit),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    assert_eq!(created_key.rate_limit, Some(rate_limit));
+
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys[0].rate_limit, Some(rate_limit));
+
+    let lookup_response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(lookup_response.status_code(), 200);
+    assert_eq!(lookup_response.header("ratelimit-limit"), "2");
+    assert_eq!(lookup_response.header("ratelimit-remaining"), "1");
+    let looked_up_key = lookup_response.json::<LookedUpApiKey>();
+    assert_eq!(looked_up_key.key.id, created_key.id);
+    assert_eq!(looked_up_key.rate_limit_status.unwrap().remaining, 1);
+
+    let verify_response = client
+        .verify_key(created_key.secret.clone(), "service_token")
+        .await;
+    assert_eq!(verify_response.status_code(), 200);
+    assert_eq!(verify_response.header("ratelimit-remaining"), "0");
+    let verified_key = verify_response.json::<VerifiedApiKey>();
+    assert_eq!(verified_key.rate_limit_status.unwrap().remaining, 0);
+
+    let limited_response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(limited_response.status_code(), 429);
+    assert_eq!(limited_response.header("ratelimit-remaining"), "0");
+    assert_eq!(limited_response.header("retry-after"), "30");
+    let error = limited_response.json::<ApiError>();
+    assert_eq!(error.code, "rate_limited");
+    let status = error.rate_limit_status.unwrap();
+    assert!(!status.allowed);
+    assert!(status.retry_at.is_some());
+
+    let now = Utc::now();
+    let rate_limit = RateLimit {
+        requests: 1,
+        period_seconds: 1,
+    };
+    let storage_adapter = &client.storage_adapter;
+    let status = storage_adapter
+        .consume_rate_limit(created_key.id, &rate_limit, now)
+        .await
+        .unwrap();
+    assert!(status.allowed);
+    assert_eq!(status.remaining, 0);
+    let status = storage_adapter
+        .consume_rate_limit(created_key.id, &rate_limit, now)
+        .await
+        .unwrap();
+    assert!(!status.allowed);
+    assert_eq!(
+        status.retry_at.map(|retry_at| retry_at.timestamp_millis()),
+        Some((now + chrono::Duration::seconds(1)).timestamp_millis())
+    );
+    let status = storage_adapter
+        .consume_rate_limit(
+            created_key.id,
+            &rate_limit,
+            now + chrono::Duration::seconds(1),
+        )
+        .await
+        .unwrap();
+    assert!(status.allowed);
+
+    // Keys that no longer exist are limited all the same.
+    let status = storage_adapter
+        .consume_rate_limit(Uuid::new_v4(), &rate_limit, now)
+        .await
+        .unwrap();
+    assert!(status.allowed);
+}
+
+async fn test_usage_quota(client: TestClient) {
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                monthly_quota: Some(2),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    assert_eq!(created_key.monthly_quota, Some(2));
+
+    let looked_up_key = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await
+        .json::<LookedUpApiKey>();
+    assert_eq!(looked_up_key.key.monthly_quota, Some(2));
+    let quota_status = looked_up_key.quota_status.unwrap();
+    assert!(quota_status.allowed);
+    assert_eq!(quota_status.used, 1);
+    assert_eq!(quota_status.remaining, 1);
+
+    let verified_key = client
+        .verify_key(created_key.secret.clone(), "service_token")
+        .await
+        .json::<VerifiedApiKey>();
+    assert_eq!(verified_key.quota_status.unwrap().remaining, 0);
+
+    let over_quota_response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(over_quota_response.status_code(), 402);
+    let error = over_quota_response.json::<ApiError>();
+    assert_eq!(error.code, "quota_exceeded");
+    let quota_status = error.quota_status.unwrap();
+    assert!(!quota_status.allowed);
+    assert_eq!(quota_status.used, 2);
+    assert!(quota_status.reset_at > Utc::now());
+    assert_eq!(quota_status.reset_at.day(), 1);
+
+    let usage = client
+        .get_usage(created_key.id, None, "test_token")
+        .await
+        .json::<KeyUsage>();
+    assert_eq!(usage.key_id, created_key.id);
+    assert_eq!(usage.monthly_quota, Some(2));
+    assert_eq!(usage.used, 2);
+    assert_eq!(usage.remaining, Some(0));
+    assert_eq!(
+        usage.daily,
+        vec![DailyUsage {
+            date: Utc::now().date_naive(),
+            requests: 2
+        }]
+    );
+
+    let last_month = Utc::now() - chrono::Days::new(Utc::now().day() as u64);
+    let storage_adapter = &client.storage_adapter;
+    assert_eq!(
+        storage_adapter
+            .increment_usage(created_key.id, Some(2), last_month)
+            .await
+            .unwrap(),
+        (true, 1)
+    );
+    let usage = client
+        .get_usage(
+            created_key.id,
+            Some(&last_month.format("%Y-%m").to_string()),
+            "test_token",
+        )
+        .await
+        .json::<KeyUsage>();
+    assert_eq!(usage.from, calendar_month(last_month.date_naive()).0);
+    assert_eq!(usage.used, 1);
+    assert_eq!(usage.daily[0].date, last_month.date_naive());
+
+    // Refunds take back counted uses, but never more than were counted.
+    for _ in 0..2 {
+        storage_adapter
+            .refund_usage(created_key.id, last_month)
+            .await
+            .unwrap();
+    }
+    let (from, until) = calendar_month(last_month.date_naive());
+    assert!(storage_adapter
+        .get_usage(created_key.id, from, until)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        storage_adapter
+            .increment_usage(created_key.id, Some(1), last_month)
+            .await
+            .unwrap(),
+        (true, 1)
+    );
+
+    let unlimited_key = client
+        .create_key(
+            InputApiKey {
+                name: "my other api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    let looked_up_key = client
+        .lookup_key(unlimited_key.secret, "test_token")
+        .await
+        .json::<LookedUpApiKey>();
+    assert!(looked_up_key.quota_status.is_none());
+    let usage = client
+        .get_usage(unlimited_key.id, None, "test_token")
+        .await
+        .json::<KeyUsage>();
+    assert_eq!(usage.used, 1);
+    assert_eq!(usage.remaining, None);
+
+    let response = client.get_usage(created_key.id, None, "other_token").await;
+    assert_eq!(response.status_code(), 404);
+    let response = client
+        .get_usage(created_key.id, Some("last month"), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    // Uses of keys that no longer exist are counted all the same.
+    let deleted_key_id = Uuid::new_v4();
+    for expected in [(true, 1), (false, 1)] {
+        assert_eq!(
+            storage_adapter
+                .increment_usage(deleted_key_id, Some(1), Utc::now())
+                .await
+                .unwrap(),
+            expected
+        );
+    }
+
+    client
+        .delete_key(created_key.id, "test_token")
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    let (from, until) = calendar_month(last_month.date_naive());
+    assert!(storage_adapter
+        .get_usage(created_key.id, from, until + chrono::Months::new(1))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+async fn test_organization_keys(client: TestClient) {
+    let created_key = client
+        .server
+        .post("/organizations/acme/keys")
+        .json(&InputApiKey {
+            name: "team key".to_string(),
+            ..Default::default()
+        })
+        .add_header("Authorization", "Bearer org_admin_token")
+        .await
+        .json::<ApiKey>();
+
+    for token in ["org_member_token", "test_token"] {
+        let response = client
+            .server
+            .get("/organizations/acme/keys")
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await;
+        assert_eq!(response.status_code(), 403);
+        let response = client
+            .server
+            .delete(&format!("/organizations/acme/keys/{}", created_key.id))
+            .add_header("Authorization", &format!("Bearer {}", token))
+            .await;
+        assert_eq!(response.status_code(), 403);
+    }
+    let response = client
+        .server
+        .get("/organizations/globex/keys")
+        .add_header("Authorization", "Bearer org_admin_token")
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    let organization_keys = client
+        .server
+        .get("/organizations/acme/keys")
+        .add_header("Authorization", "Bearer org_admin_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(organization_keys.len(), 1);
+    assert_eq!(organization_keys[0].id, created_key.id);
+    let user_keys = client
+        .list_keys("org_admin_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert!(user_keys.is_empty());
+
+    let response = client
+        .lookup_key(created_key.secret.clone(), "org_member_token")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let regenerated_key = client
+        .server
+        .post(&format!("/organizations/acme/keys/{}", created_key.id))
+        .add_header("Authorization", "Bearer org_admin_token")
+        .await
+        .json::<ApiKey>();
+    assert_ne!(regenerated_key.secret, created_key.secret);
+    let response = client
+        .lookup_key(created_key.secret, "org_member_token")
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let verified_key = client
+        .verify_key(regenerated_key.secret, "service_token")
+        .await
+        .json::<VerifiedApiKey>();
+    assert_eq!(verified_key.owner, Owner::organization("acme"));
+
+    let response = client
+        .server
+        .delete(&format!("/organizations/acme/keys/{}", created_key.id))
+        .add_header("Authorization", "Bearer org_admin_token")
+        .await;
+    assert_eq!(response.status_code(), 204);
+    assert!(client
+        .storage_adapter
+        .list_keys(&Owner::organization("acme"))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_organization_claims() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_organization_directory(ClaimOrganizationDirectory::new(
+            "org_id",
+            Some("org_role".to_string()),
+        )),
+        InMemoryStorage::new(),
+    );
+    let token = |claims: serde_json::Value| {
+        jsonwebtoken::encode(
+            &Default::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"test"),
+        )
+        .unwrap()
+    };
+    let admin_token = token(serde_json::json!({ "org_id": "acme", "org_role": "admin" }));
+    let member_token = token(serde_json::json!({ "org_id": ["acme"], "org_role": [] }));
+    let outsider_token = token(serde_json::json!({ "org_id": "globex" }));
+
+    let created_key = client
+        .server
+        .post("/organizations/acme/keys")
+        .json(&InputApiKey {
+            name: "team key".to_string(),
+            ..Default::default()
+        })
+        .add_header("Authorization", &format!("Bearer {}", admin_token))
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .server
+        .get("/organizations/acme/keys")
+        .add_header("Authorization", &format!("Bearer {}", member_token))
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    let response = client
+        .lookup_key(created_key.secret.clone(), &member_token)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = client.lookup_key(created_key.secret, &outsider_token).await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_owner_resolver() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_owner_resolver(ClaimOwnerResolver::new("client_id")),
+        InMemoryStorage::new(),
+    );
+    let token = |claims: serde_json::Value| {
+        jsonwebtoken::encode(
+            &Default::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"test"),
+        )
+        .unwrap()
+    };
+    let machine_token = token(serde_json::json!({ "client_id": "billing" }));
+    let other_machine_token = token(serde_json::json!({ "client_id": "billing", "n": 1 }));
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "machine key".to_string(),
+                ..Default::default()
+            },
+            &machine_token,
+        )
+        .await
+        .json::<ApiKey>();
+    let listed_keys = client
+        .list_keys(&other_machine_token)
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys.len(), 1);
+    assert_eq!(listed_keys[0].id, created_key.id);
+
+    let verified_key = client
+        .verify_key(created_key.secret, "service_token")
+        .await
+        .json::<VerifiedApiKey>();
+    assert_eq!(verified_key.owner, Owner::user("billing"));
+
+    let response = client.list_keys("test_token").await;
+    assert_eq!(response.status_code(), 401);
+}
+
+#[tokio::test]
+async fn test_template_owner_resolver() {
+    let claims = TokenClaims(serde_json::Map::from_iter([
+        ("tenant_id".to_string(), "acme".into()),
+        ("client_id".to_string(), 42.into()),
+        ("azp".to_string(), "".into()),
+    ]));
+
+    let resolver = TemplateOwnerResolver::new("tenant:{tenant_id}/{client_id}").unwrap();
+    assert_eq!(
+        resolver.resolve(&claims).await,
+        Some("tenant:acme/42".to_string())
+    );
+    let resolver = TemplateOwnerResolver::new("{tenant_id}/{azp}").unwrap();
+    assert_eq!(resolver.resolve(&claims).await, None);
+    let resolver = TemplateOwnerResolver::new("{tenant_id}/{sub}").unwrap();
+    assert_eq!(resolver.resolve(&claims).await, None);
+
+    for template in [
+        "",
+        "tenant",
+        "{tenant_id",
+        "tenant_id}",
+        "{}",
+        "{{tenant_id}}",
+    ] {
+        assert!(TemplateOwnerResolver::new(template).is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_admin_routes() {
+    let directory = tempfile::tempdir().unwrap();
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder()
+            .with_admin_role(AdminRole::Scope("keys:admin".to_string()))
+            .with_audit_sink(audit_sink::JsonLinesAuditSink::new(
+                directory.path().join("audit.jsonl"),
+            )),
+        InMemoryStorage::new(),
+    );
+    let admin_token = jsonwebtoken::encode(
+        &Default::default(),
+        &serde_json::json!({ "sub": "support_admin", "scope": "openid keys:admin" }),
+        &jsonwebtoken::EncodingKey::from_secret(b"test"),
+    )
+    .unwrap();
+    let admin_header = format!("Bearer {}", admin_token);
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "leaked key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .server
+        .get("/admin/users/test_token/keys")
+        .add_header("Authorization", "Bearer test_token")
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    let response = client
+        .server
+        .get("/admin/users/test_token/keys")
+        .add_header("Authorization", &admin_header)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header(ADMIN_ACTOR_HEADER), "support_admin");
+    let listed_keys = response.json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys.len(), 1);
+    assert_eq!(listed_keys[0].id, created_key.id);
+
+    let response = client
+        .server
+        .post("/admin/keys/search")
+        .json(&KeySearch {
+            secret: created_key.secret.clone(),
+        })
+        .add_header("Authorization", &admin_header)
+        .await;
+    assert_eq!(response.header(ADMIN_ACTOR_HEADER), "support_admin");
+    let found_key = response.json::<OwnedApiKey>();
+    assert_eq!(found_key.owner, Owner::user("test_token"));
+    assert_eq!(found_key.key.id, created_key.id);
+    let response = client
+        .server
+        .post("/admin/keys/search")
+        .json(&KeySearch {
+            secret: "unknown secret".to_string(),
+        })
+        .add_header("Authorization", &admin_header)
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let regenerated_key = client
+        .server
+        .post(&format!("/admin/users/test_token/keys/{}", created_key.id))
+        .add_header("Authorization", &admin_header)
+        .await
+        .json::<ApiKey>();
+    assert_eq!(regenerated_key.id, created_key.id);
+    let response = client.lookup_key(created_key.secret, "test_token").await;
+    assert_eq!(response.status_code(), 404);
+
+    let response = client
+        .server
+        .delete(&format!("/admin/users/other_token/keys/{}", created_key.id))
+        .add_header("Authorization", &admin_header)
+        .await;
+    assert_eq!(response.status_code(), 404);
+    let response = client
+        .server
+        .delete(&format!("/admin/users/test_token/keys/{}", created_key.id))
+        .add_header("Authorization", &admin_header)
+        .await;
+    assert_eq!(response.status_code(), 204);
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert!(listed_keys.is_empty());
+
+    let events = client
+        .server
+        .get("/audit")
+        .add_header("Authorization", &admin_header)
+        .await
+        .json::<Vec<AuditEvent>>();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (
+                event.action,
+                event.admin,
+                event.target_user.as_deref(),
+                event.outcome
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            (AuditAction::Search, true, None, AuditOutcome::Success),
+            (AuditAction::Search, true, None, AuditOutcome::NotFound),
+            (
+                AuditAction::Regenerate,
+                true,
+                Some("test_token"),
+                AuditOutcome::Success
+            ),
+            (
+                AuditAction::Delete,
+                true,
+                Some("other_token"),
+                AuditOutcome::NotFound
+            ),
+            (
+                AuditAction::Delete,
+                true,
+                Some("test_token"),
+                AuditOutcome::Success
+            ),
+        ]
+    );
+
+    // Users see what admins did to their keys.
+    let events = client
+        .server
+        .get("/audit")
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .json::<Vec<AuditEvent>>();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.action, event.actor.as_str(), event.admin))
+            .collect::<Vec<_>>(),
+        vec![
+            (AuditAction::Create, "test_token", false),
+            (AuditAction::Regenerate, "support_admin", true),
+            (AuditAction::Lookup, "test_token", false),
+            (AuditAction::Delete, "support_admin", true),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_claims_are_read_from_verified_tokens_only() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_admin_role(AdminRole::Scope("keys:admin".to_string())),
+        InMemoryStorage::new(),
+    );
+    let token = |secret: &[u8]| {
+        jsonwebtoken::encode(
+            &Default::default(),
+            &serde_json::json!({ "sub": "support_admin", "scope": "keys:admin" }),
+            &jsonwebtoken::EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    };
+
+    let response = client
+        .server
+        .get("/admin/users/test_token/keys")
+        .add_header("Authorization", &format!("bearer {}", token(b"test")))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header(ADMIN_ACTOR_HEADER), "support_admin");
+
+    let response = client
+        .server
+        .get("/admin/users/test_token/keys")
+        .add_header("Authorization", &format!("Bearer {}", token(b"forged")))
+        .await;
+    assert_eq!(response.status_code(), 403);
+}
+
+#[tokio::test]
+async fn test_admin_requests_are_logged() {
+    use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt};
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = Logs::default();
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            }),
+    );
+    let guard = tracing::subscriber::set_default(subscriber);
+
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_admin_role(AdminRole::Scope("keys:admin".to_string())),
+        InMemoryStorage::new(),
+    );
+    let admin_token = jsonwebtoken::encode(
+        &Default::default(),
+        &serde_json::json!({ "sub": "support_admin", "scope": "keys:admin" }),
+        &jsonwebtoken::EncodingKey::from_secret(b"test"),
+    )
+    .unwrap();
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "leaked key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    let response = client
+        .server
+        .delete(&format!("/admin/users/test_token/keys/{}", created_key.id))
+        .add_header("Authorization", &format!("Bearer {}", admin_token))
+        .await;
+    assert_eq!(response.status_code(), 204);
+    drop(guard);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let request_log = logs
+        .lines()
+        .find(|line| line.contains("Admin request"))
+        .unwrap();
+    assert!(request_log.contains("admin.actor=support_admin"));
+    assert!(request_log.contains(r#"admin.target_user="test_token""#));
+    assert!(request_log.contains(r#"route="/admin/users/:user_id/keys/:id""#));
+    assert!(request_log.contains("status=204"));
+    assert!(logs.lines().any(|line| line.contains("delete_key{")
+        && line.contains("owner.id=test_token")
+        && line.contains(r#"admin.actor="support_admin""#)));
+    assert!(!logs
+        .lines()
+        .any(|line| line.contains("create_key{") && line.contains("admin.actor")));
+}
+
+#[tokio::test]
+async fn test_admin_routes_disabled_without_admin_role() {
+    let server = TestServer::new(router_with_secret_hasher(
+        TestAuthProvider::new(),
+        InMemoryStorage::new(),
+        UuidSecretGenerator::new(),
+        Sha256SecretHasher::new("test_pepper"),
+    ))
+    .unwrap();
+
+    let response = server
+        .get("/admin/users/test_token/keys")
+        .add_header("Authorization", "Bearer test_token")
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[test]
+fn test_admin_role() {
+    let claims = TokenClaims(serde_json::Map::from_iter([
+        ("roles".to_string(), serde_json::json!(["support", "admin"])),
+        ("scp".to_string(), serde_json::json!(["keys:admin"])),
+        ("scope".to_string(), "openid keys:read".into()),
+    ]));
+
+    let role = |name: &str, value: &str| AdminRole::Claim {
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+    assert!(role("roles", "admin").is_granted(&claims));
+    assert!(!role("roles", "owner").is_granted(&claims));
+    assert!(!role("groups", "admin").is_granted(&claims));
+    assert!(AdminRole::Scope("keys:admin".to_string()).is_granted(&claims));
+    assert!(AdminRole::Scope("keys:read".to_string()).is_granted(&claims));
+    assert!(!AdminRole::Scope("keys:write".to_string()).is_granted(&claims));
+}
+
+/// Marks responses with the route that handled the request, if any. Axum
+/// reports a matched path for method-not-allowed responses too, so those
+/// are left unmarked.
+async fn mark_matched_route(matched_path: MatchedPath, request: Request, next: Next) -> Response {
+    let route = format!("{} {}", request.method(), matched_path.as_str());
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        response
+            .headers_mut()
+            .insert("x-matched-route", route.parse().unwrap());
+    }
+    response
+}
+
+/// The paths of every route of `router`. Axum offers no way to list them, so
+/// they are read from its `Debug` output.
+fn router_paths(router: &Router) -> Vec<String> {
+    format!("{:?}", router)
+        .split("): \"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .filter(|path| path.starts_with('/') && !path.contains("__private__axum"))
+        .map(str::to_string)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_openapi_matches_routes() {
+    let directory = tempfile::tempdir().unwrap();
+    let builders = [
+        ApiKeyServer::builder(),
+        ApiKeyServer::builder()
+            .with_service_authenticator(StaticTokenAuthenticator::new("service_token"))
+            .with_audit_sink(audit_sink::JsonLinesAuditSink::new(
+                directory.path().join("audit.jsonl"),
+            ))
+            .with_organization_directory(StaticOrganizationDirectory::new([OrganizationMember {
+                user_id: "service_token".to_string(),
+                organization_id: "acme".to_string(),
+                role: OrganizationRole::Admin,
+            }]))
+            .with_admin_role(AdminRole::Claim {
+                name: "sub".to_string(),
+                value: "service_token".to_string(),
+            }),
+    ];
+
+    for builder in builders {
+        let api_key_server = builder
+            .with_storage_adapter(InMemoryStorage::new())
+            .with_auth_provider(TestAuthProvider::new())
+            .with_secret_generator(UuidSecretGenerator::new())
+            .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+            .build()
+            .unwrap();
+
+        let openapi = api_key_server.openapi();
+        let documented = openapi
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                let path = path.replace('{', ":").replace('}', "");
+                [
+                    (Method::GET, &item.get),
+                    (Method::POST, &item.post),
+                    (Method::PUT, &item.put),
+                    (Method::PATCH, &item.patch),
+                    (Method::DELETE, &item.delete),
+                ]
+                .into_iter()
+                .filter(|(_, operation)| operation.is_some())
+                .map(move |(method, _)| format!("{} {}", method, path))
+            })
+            .collect::<HashSet<String>>();
+
+        let router = api_key_server.router();
+        let paths = router_paths(&router);
+        assert!(!paths.is_empty());
+        let server =
+            TestServer::new(router.route_layer(middleware::from_fn(mark_matched_route))).unwrap();
+        // Probe as a user that gets past every authentication and role
+        // check, so that only the routing decides the response.
+        let mut routed = HashSet::new();
+        for path in paths {
+            let uri = path
+                .split('/')
+                .map(|segment| match segment {
+                    ":id" => Uuid::new_v4().to_string(),
+                    ":organization_id" => "acme".to_string(),
+                    segment => segment.trim_start_matches(':').to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join("/");
+            for method in [
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ] {
+                let response = server
+                    .method(method, &uri)
+                    .authorization_bearer("service_token")
+                    .await;
+                if let Some(route) = response.headers().get("x-matched-route") {
+                    routed.insert(route.to_str().unwrap().to_string());
+                }
+            }
+        }
+
+        assert_eq!(routed, documented);
+    }
+}
+
+#[tokio::test]
+async fn test_openapi_document() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    let response = client.server.get("/openapi.json").await;
+    assert_eq!(response.status_code(), 200);
+    let openapi = response.json::<serde_json::Value>();
+    assert!(openapi["openapi"].as_str().unwrap().starts_with("3.1"));
+    assert_eq!(
+        openapi["components"]["securitySchemes"]["bearer"]["scheme"],
+        "bearer"
+    );
+    let delete_key = &openapi["paths"]["/keys/{id}"]["delete"];
+    assert_eq!(delete_key["security"][0]["bearer"], serde_json::json!([]));
+    assert!(delete_key["responses"]["404"].is_object());
+    assert!(delete_key["responses"]["500"].is_object());
+    let delete_organization_key =
+        &openapi["paths"]["/organizations/{organization_id}/keys/{id}"]["delete"];
+    assert_eq!(
+        delete_organization_key["operationId"],
+        "organization_delete_key"
+    );
+    assert!(delete_organization_key["responses"]["403"].is_object());
+    for schema in ["InputApiKey", "ApiKey", "ProtectedApiKey", "LookupSecret"] {
+        assert!(openapi["components"]["schemas"][schema].is_object());
+    }
+}
+
+#[tokio::test]
+async fn test_swagger_ui() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_swagger_ui(),
+        InMemoryStorage::new(),
+    );
+
+    let response = client.server.get("/swagger-ui/").await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("swagger"));
+    let response = client
+        .server
+        .get("/swagger-ui/swagger-initializer.js")
+        .await;
+    assert!(response.text().contains("/openapi.json"));
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_cors_allowed_origins(["https://app.example.com"]),
+        InMemoryStorage::new(),
+    );
+
+    let response = client
+        .server
+        .method(Method::OPTIONS, "/keys")
+        .add_header("Origin", "https://app.example.com")
+        .add_header("Access-Control-Request-Method", "POST")
+        .add_header("Access-Control-Request-Headers", "authorization")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(
+        response.header("access-control-allow-origin"),
+        "https://app.example.com"
+    );
+    assert!(response
+        .header("access-control-allow-headers")
+        .to_str()
+        .unwrap()
+        .contains("authorization"));
+
+    let response = client
+        .server
+        .get("/keys")
+        .add_header("Origin", "https://app.example.com")
+        .authorization_bearer("user")
+        .await;
+    assert_eq!(
+        response.header("access-control-allow-origin"),
+        "https://app.example.com"
+    );
+    assert!(response
+        .header("access-control-expose-headers")
+        .to_str()
+        .unwrap()
+        .contains("ratelimit-remaining"));
+
+    let response = client
+        .server
+        .get("/keys")
+        .add_header("Origin", "https://evil.example.com")
+        .authorization_bearer("user")
+        .await;
+    assert!(response
+        .maybe_header("access-control-allow-origin")
+        .is_none());
+
+    let client = TestClient::new(InMemoryStorage::new());
+    let response = client
+        .server
+        .get("/keys")
+        .add_header("Origin", "https://app.example.com")
+        .authorization_bearer("user")
+        .await;
+    assert!(response
+        .maybe_header("access-control-allow-origin")
+        .is_none());
+
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_cors_allowed_origins(["*"]),
+        InMemoryStorage::new(),
+    );
+    let response = client
+        .server
+        .get("/keys")
+        .add_header("Origin", "https://any.example.com")
+        .authorization_bearer("user")
+        .await;
+    assert_eq!(response.header("access-control-allow-origin"), "*");
+
+    assert!(ApiKeyServer::builder()
+        .with_auth_provider(TestAuthProvider::new())
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+        .with_storage_adapter(InMemoryStorage::new())
+        .with_cors_allowed_origins(["https://app.example.com\n"])
+        .build()
+        .is_err());
+}
+
+struct CertificateAuthority {
+    certificate: rcgen::Certificate,
+    key_pair: rcgen::KeyPair,
+}
+
+impl CertificateAuthority {
+    fn new() -> Self {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        Self {
+            certificate: params.self_signed(&key_pair).unwrap(),
+            key_pair,
+        }
+    }
+
+    fn pem(&self) -> String {
+        self.certificate.pem()
+    }
+
+    /// Issues a certificate for `name`, returned as PEM with its key.
+    fn issue(&self, name: &str) -> (String, String) {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let certificate = rcgen::CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key_pair, &self.certificate, &self.key_pair)
+            .unwrap();
+        (certificate.pem(), key_pair.serialize_pem())
+    }
+}
+
+/// Runs a server with `tls` and returns the address it listens on.
+async fn serve_tls(tls: Arc<tls::TlsConfig>) -> std::net::SocketAddr {
+    let api_key_server = ApiKeyServer::builder()
+        .with_auth_provider(TestAuthProvider::new())
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+        .with_storage_adapter(InMemoryStorage::new())
+        .with_tls(tls)
+        .with_tls_reload_interval(Duration::from_millis(20))
+        .build()
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(api_key_server.run(listener));
+    address
+}
+
+/// Checks the health of the server at `address` from a new client, which
+/// only trusts `ca` and presents `identity` if any.
+async fn https_healthz(
+    address: std::net::SocketAddr,
+    ca: &CertificateAuthority,
+    identity: Option<(String, String)>,
+) -> Result<reqwest::StatusCode, reqwest::Error> {
+    let mut client = reqwest::Client::builder()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(reqwest::Certificate::from_pem(ca.pem().as_bytes()).unwrap())
+        .resolve("localhost", address);
+    if let Some((certificate, key)) = identity {
+        client =
+            client.identity(reqwest::Identity::from_pem((certificate + &key).as_bytes()).unwrap());
+    }
+    let response = client
+        .build()
+        .unwrap()
+        .get(format!("https://localhost:{}/healthz", address.port()))
+        .send()
+        .await?;
+    Ok(response.status())
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let directory = tempfile::tempdir().unwrap();
+    let cert_path = directory.path().join("cert.pem");
+    let key_path = directory.path().join("key.pem");
+    let write_certificate = |(certificate, key): (String, String)| {
+        std::fs::write(&cert_path, certificate).unwrap();
+        std::fs::write(&key_path, key).unwrap();
+    };
+    let ca = CertificateAuthority::new();
+    let rotated_ca = CertificateAuthority::new();
+
+    write_certificate(ca.issue("localhost"));
+    let tls = tls::TlsConfig::new(&cert_path, &key_path, None).unwrap();
+    let address = serve_tls(tls.clone()).await;
+
+    assert_eq!(https_healthz(address, &ca, None).await.unwrap(), 200);
+    assert!(https_healthz(address, &rotated_ca, None).await.is_err());
+    assert!(reqwest::get(format!("http://{}/healthz", address))
+        .await
+        .is_err());
+    assert_eq!(tls.reload(), Ok(false));
+
+    write_certificate(rotated_ca.issue("localhost"));
+    let mut attempts = 0;
+    while https_healthz(address, &rotated_ca, None).await.is_err() {
+        attempts += 1;
+        assert!(attempts < 100, "The certificate was not reloaded");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(https_healthz(address, &ca, None).await.is_err());
+
+    std::fs::write(&cert_path, "not a certificate").unwrap();
+    assert!(tls.reload().is_err());
+    assert_eq!(
+        https_healthz(address, &rotated_ca, None).await.unwrap(),
+        200
+    );
+
+    assert!(tls::TlsConfig::new(&cert_path, &key_path, None).is_err());
+    assert!(tls::TlsConfig::new(directory.path().join("missing.pem"), &key_path, None).is_err());
+}
+
+#[tokio::test]
+async fn test_tls_client_certificates() {
+    let directory = tempfile::tempdir().unwrap();
+    let cert_path = directory.path().join("cert.pem");
+    let key_path = directory.path().join("key.pem");
+    let client_ca_path = directory.path().join("client_ca.pem");
+    let ca = CertificateAuthority::new();
+    let client_ca = CertificateAuthority::new();
+    let (certificate, key) = ca.issue("localhost");
+    std::fs::write(&cert_path, certificate).unwrap();
+    std::fs::write(&key_path, key).unwrap();
+    std::fs::write(&client_ca_path, client_ca.pem()).unwrap();
+
+    let tls = tls::TlsConfig::new(&cert_path, &key_path, Some(client_ca_path)).unwrap();
+    let address = serve_tls(tls).await;
+
+    assert_eq!(
+        https_healthz(address, &ca, Some(client_ca.issue("client")))
+            .await
+            .unwrap(),
+        200
+    );
+    assert!(https_healthz(address, &ca, None).await.is_err());
+    assert!(https_healthz(address, &ca, Some(ca.issue("client")))
+        .await
+        .is_err());
+}
+
+/// Verifies tokens like [`TestAuthProvider`], after a delay.
+struct SlowAuthProvider(Duration);
+
+#[async_trait]
+impl AuthProvider for SlowAuthProvider {
+    async fn jwk_set(&self) -> Result<JwkSet, AuthError> {
+        TestAuthProvider::new().jwk_set().await
+    }
+
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, AuthError> {
+        tokio::time::sleep(self.0).await;
+        TestAuthProvider::new().verify(token).await
+    }
+}
+
+#[derive(Default)]
+struct FlushedAuditSink {
+    flushed: AtomicBool,
+}
+
+#[async_trait]
+impl AuditSink for FlushedAuditSink {
+    async fn record(&self, _event: &AuditEvent) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), StorageError> {
+        self.flushed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Runs a server that takes `verify_delay` to authenticate each request
+/// until the returned sender is used, and returns its address and task.
+async fn serve_until_shutdown(
+    builder: ApiKeyServerBuilder,
+    verify_delay: Duration,
+) -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Sender<()>,
+    tokio::task::JoinHandle<std::io::Result<()>>,
+) {
+    let api_key_server = builder
+        .with_auth_provider(Arc::new(SlowAuthProvider(verify_delay)))
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+        .build()
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(api_key_server.run_until(listener, async {
+        let _ = shutdown_received.await;
+    }));
+    (address, shutdown, server)
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let storage_adapter = InMemoryStorage::new();
+    let audit_sink = Arc::new(FlushedAuditSink::default());
+    let (address, shutdown, server) = serve_until_shutdown(
+        ApiKeyServer::builder()
+            .with_storage_adapter(storage_adapter.clone())
+            .with_audit_sink(audit_sink.clone()),
+        Duration::from_millis(300),
+    )
+    .await;
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .post(format!("http://{}/keys", address))
+            .bearer_auth("user")
+            .json(&serde_json::json!({ "name": "my api key" }))
+            .send(),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.send(()).unwrap();
+
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    let created_key = response.json::<ApiKey>().await.unwrap();
+    assert!(!created_key.secret.is_empty());
+    assert_eq!(storage_adapter.count_keys().await.unwrap(), 1);
+
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(audit_sink.flushed.load(Ordering::Relaxed));
+    assert!(reqwest::get(format!("http://{}/healthz", address))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_drain_timeout() {
+    let (address, shutdown, server) = serve_until_shutdown(
+        ApiKeyServer::builder()
+            .with_storage_adapter(InMemoryStorage::new())
+            .with_drain_timeout(Duration::from_millis(100)),
+        Duration::from_secs(60),
+    )
+    .await;
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .get(format!("http://{}/keys", address))
+            .bearer_auth("user")
+            .send(),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.send(()).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(request.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_healthz_while_draining() {
+    let api_key_server = ApiKeyServer::builder()
+        .with_auth_provider(TestAuthProvider::new())
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+        .with_storage_adapter(InMemoryStorage::new())
+        .build()
+        .unwrap();
+    let draining = api_key_server.draining.clone();
+    let server = TestServer::new(api_key_server.router()).unwrap();
+
+    server.get("/healthz").await.assert_status_ok();
+
+    draining.store(true, Ordering::Relaxed);
+    let response = server.get("/healthz").await;
+    assert_eq!(response.status_code(), 503);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.code, "shutting_down");
+}
+
+#[tokio::test]
+async fn test_readyz() {
+    struct UnavailableAuthProvider;
+
+    #[async_trait]
+    impl AuthProvider for UnavailableAuthProvider {
+        async fn jwk_set(&self) -> Result<JwkSet, AuthError> {
+            Err(AuthError::Other("JWKS endpoint unreachable".to_string()))
+        }
+
+        async fn verify(&self, _token: &str) -> Result<TokenData<Claims>, AuthError> {
+            unreachable!()
+        }
+    }
+
+    let readyz = |auth_provider: Arc<dyn AuthProvider>,
+                  storage_adapter: Arc<dyn StorageAdapter>,
+                  draining: bool| async move {
+        let api_key_server = ApiKeyServer::builder()
+            .with_auth_provider(auth_provider)
+            .with_secret_generator(UuidSecretGenerator::new())
+            .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+            .with_storage_adapter(storage_adapter)
+            .build()
+            .unwrap();
+        api_key_server.draining.store(draining, Ordering::Relaxed);
+        let server = TestServer::new(api_key_server.router()).unwrap();
+        let response = server.get("/readyz").await;
+        assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+        (response.status_code(), response.json::<Readiness>())
+    };
+
+    let (status, readiness) = readyz(TestAuthProvider::new(), InMemoryStorage::new(), false).await;
+    assert_eq!(status, 200);
+    assert_eq!(readiness.status, HealthStatus::Up);
+    assert!(!readiness.draining);
+    assert_eq!(readiness.storage.status, HealthStatus::Up);
+    assert_eq!(readiness.auth_provider.status, HealthStatus::Up);
+    assert!(readiness.storage.latency_ms >= 0.0);
+
+    let (status, readiness) = readyz(
+        TestAuthProvider::new(),
+        Arc::new(FailingStorage(|| {
+            StorageError::unavailable("connection refused")
+        })),
+        false,
+    )
+    .await;
+    assert_eq!(status, 503);
+    assert_eq!(readiness.status, HealthStatus::Down);
+    assert_eq!(readiness.storage.status, HealthStatus::Down);
+    assert_eq!(readiness.auth_provider.status, HealthStatus::Up);
+
+    let (status, readiness) = readyz(
+        Arc::new(UnavailableAuthProvider),
+        InMemoryStorage::new(),
+        false,
+    )
+    .await;
+    assert_eq!(status, 503);
+    assert_eq!(readiness.storage.status, HealthStatus::Up);
+    assert_eq!(readiness.auth_provider.status, HealthStatus::Down);
+
+    let (status, readiness) = readyz(TestAuthProvider::new(), InMemoryStorage::new(), true).await;
+    assert_eq!(status, 503);
+    assert_eq!(readiness.status, HealthStatus::Down);
+    assert!(readiness.draining);
+    assert_eq!(readiness.storage.status, HealthStatus::Up);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_storage_health_check() {
+    let storage_adapter = sqlite_storage::SqliteStorage::open_in_memory().unwrap();
+    storage_adapter.health_check().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_livez_and_version() {
+    let client = TestClient::new(InMemoryStorage::new());
+    client.server.get("/livez").await.assert_status_ok();
+
+    let response = client.server.get("/version").await;
+    response.assert_status_ok();
+    let version = response.json::<Version>();
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        version.features.contains(&"sqlite".to_string()),
+        cfg!(feature = "sqlite")
+    );
+    assert_eq!(
+        version.features.contains(&"postgres".to_string()),
+        cfg!(feature = "postgres")
+    );
+}
+
+#[tokio::test]
+async fn test_otlp_export() {
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Exports = Arc<Mutex<Vec<u8>>>;
+
+    let exports = Exports::default();
+    let collector = Router::new()
+        .route(
+            "/v1/traces",
+            post(
+                |State(exports): State<Exports>, body: axum::body::Bytes| async move {
+                    exports.lock().await.extend_from_slice(&body);
+                    StatusCode::OK
+                },
+            ),
+        )
+        .with_state(exports.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, collector).await });
+
+    let provider =
+        telemetry::otlp_tracer_provider(&format!("http://{}/", address), "api-key-server").unwrap();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("api-key-server")));
+    let guard = tracing::subscriber::set_default(subscriber);
+
+    let client = TestClient::new(InMemoryStorage::new());
+    let response = client
+        .server
+        .post("/keys")
+        .json(&serde_json::json!({ "name": "Traced key" }))
+        .add_header("Authorization", "Bearer test_token")
+        .add_header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .await;
+    response.assert_status_ok();
+    let secret = response.json::<ApiKey>().secret;
+    client
+        .verify_key(secret.clone(), "service_token")
+        .await
+        .assert_status_ok();
+
+    drop(guard);
+    telemetry::shutdown(provider).await.unwrap();
+
+    let exports = exports.lock().await;
+    let contains = |bytes: &[u8]| exports.windows(bytes.len()).any(|window| window == bytes);
+    assert!(contains(b"api-key-server"));
+    assert!(contains(b"POST /keys"));
+    assert!(contains(b"storage create_key"));
+    assert!(contains(b"verify_key"));
+    // The request span continues the trace of the caller.
+    assert!(contains(
+        &0x4bf92f3577b34da6a3ce929d0e0e4736_u128.to_be_bytes()
+    ));
+    assert!(!contains(secret.as_bytes()));
+    assert!(!contains(b"Bearer"));
+    assert!(!contains(b"service_token"));
+    assert!(!contains(b"test_pepper"));
+}
+
+#[tokio::test]
+async fn test_in_memory_storage_purges_rate_limits() {
+    let storage_adapter = InMemoryStorage::new();
+    let now = Utc::now();
+    let rate_limit = RateLimit {
+        requests: 1,
+        period_seconds: 60,
+    };
+    let key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "expired key".to_string(),
+        secret: "expired secret".to_string(),
+        expires_at: Some(now - chrono::Duration::hours(1)),
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: Some(rate_limit),
+        monthly_quota: None,
+    };
+    storage_adapter
+        .create_key(&Owner::user("test_token"), key.clone())
+        .await
+        .unwrap();
+    storage_adapter
+        .consume_rate_limit(key.id, &rate_limit, now)
+        .await
+        .unwrap();
+
+    assert_eq!(storage_adapter.purge_expired_keys(now).await.unwrap(), 1);
+    let status = storage_adapter
+        .consume_rate_limit(key.id, &rate_limit, now)
+        .await
+        .unwrap();
+    assert!(status.allowed);
+}
+
+#[tokio::test]
+async fn test_storage_rate_limiter() {
+    let storage_adapter = InMemoryStorage::new();
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_storage_rate_limiter(),
+        storage_adapter,
+    );
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                rate_limit: Some(RateLimit {
+                    requests: 1,
+                    period_seconds: 60,
+                }),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .verify_key(created_key.secret.clone(), "service_token")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = client.verify_key(created_key.secret, "service_token").await;
+    assert_eq!(response.status_code(), 429);
+    assert_eq!(response.header("retry-after"), "60");
+
+    let metrics = client.server.get("/metrics").await.text();
+    assert!(metrics.contains(
+        r#"api_key_server_storage_operation_duration_seconds_count{operation="consume_rate_limit"} 2"#
+    ));
+}
+
+#[tokio::test]
+async fn test_in_memory_rate_limiter_drops_refilled_buckets() {
+    let rate_limiter = InMemoryRateLimiter::with_sweep_interval(Duration::ZERO);
+    let rate_limit = RateLimit {
+        requests: 1000,
+        period_seconds: 1,
+    };
+
+    let key_id = Uuid::new_v4();
+    rate_limiter.acquire(key_id, &rate_limit).await.unwrap();
+    rate_limiter
+        .acquire(Uuid::new_v4(), &rate_limit)
+        .await
+        .unwrap();
+    assert_eq!(rate_limiter.tracked_keys().await, 2);
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let status = rate_limiter.acquire(key_id, &rate_limit).await.unwrap();
+    assert_eq!(status.remaining, 999);
+    assert_eq!(rate_limiter.tracked_keys().await, 1);
+}
+
+#[tokio::test]
+async fn test_over_quota_requests_do_not_take_from_rate_limit() {
+    let storage_adapter = InMemoryStorage::new();
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_storage_rate_limiter(),
+        storage_adapter,
+    );
+    let rate_limit = RateLimit {
+        requests: 3,
+        period_seconds: 60,
+    };
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                rate_limit: Some(rate_limit),
+                monthly_quota: Some(1),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header("ratelimit-remaining"), "2");
+    let response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 402);
+    let response = client.verify_key(created_key.secret, "service_token").await;
+    assert_eq!(response.status_code(), 402);
+
+    let status = client
+        .storage_adapter
+        .consume_rate_limit(created_key.id, &rate_limit, Utc::now())
+        .await
+        .unwrap();
+    assert_eq!(status.remaining, 1);
+}
+
+#[tokio::test]
+async fn test_rate_limited_requests_do_not_count_usage() {
+    let client = TestClient::with_builder(
+        ApiKeyServer::builder().with_storage_rate_limiter(),
+        InMemoryStorage::new(),
+    );
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                rate_limit: Some(RateLimit {
+                    requests: 1,
+                    period_seconds: 60,
+                }),
+                monthly_quota: Some(5),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = client
+        .lookup_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 429);
+    let response = client.verify_key(created_key.secret, "service_token").await;
+    assert_eq!(response.status_code(), 429);
+
+    let usage = client
+        .get_usage(created_key.id, None, "test_token")
+        .await
+        .json::<KeyUsage>();
+    assert_eq!(usage.used, 1);
+    assert_eq!(usage.remaining, Some(4));
+}
+
+#[tokio::test]
+async fn test_create_key_with_invalid_rate_limit() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    for rate_limit in [
+        RateLimit {
+            requests: 0,
+            period_seconds: 60,
+        },
+        RateLimit {
+            requests: 10,
+            period_seconds: 0,
+        },
+        RateLimit {
+            requests: 10,
+            period_seconds: u64::MAX,
+        },
+    ] {
+        let response = client
+            .create_key(
+                InputApiKey {
+                    name: "my api key".to_string(),
+                    rate_limit: Some(rate_limit),
+                    ..Default::default()
+                },
+                "test_token",
+            )
+            .await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<ApiError>().detail,
+            "rate_limit is out of range"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_create_key_with_invalid_monthly_quota() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    for monthly_quota in [0, u64::MAX] {
+        let response = client
+            .create_key(
+                InputApiKey {
+                    name: "my api key".to_string(),
+                    monthly_quota: Some(monthly_quota),
+                    ..Default::default()
+                },
+                "test_token",
+            )
+            .await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(
+            response.json::<ApiError>().detail,
+            "monthly_quota is out of range"
+        );
+    }
+}
+
+#[test]
+fn test_calendar_month() {
+    let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+    assert_eq!(
+        calendar_month(date(2024, 2, 29)),
+        (date(2024, 2, 1), date(2024, 3, 1))
+    );
+    assert_eq!(
+        calendar_month(date(2024, 12, 1)),
+        (date(2024, 12, 1), date(2025, 1, 1))
+    );
+}
+
+#[tokio::test]
+async fn test_create_key_with_invalid_scopes() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    let response = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                scopes: vec!["orders:read".to_string(), "orders:delete".to_string()],
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.code, "invalid_scopes");
+    assert_eq!(error.detail, "Invalid scopes: orders:delete");
+
+    let list_response = client.list_keys("test_token").await;
+    assert_eq!(list_response.json::<Vec<ProtectedApiKey>>().len(), 0);
+}
+
+#[tokio::test]
+async fn test_create_key_with_expiration() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    let response = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ttl: Some(3600),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let expires_at = response.json::<ApiKey>().expires_at.unwrap();
+    assert!(expires_at > Utc::now() + chrono::Duration::minutes(59));
+    assert!(expires_at <= Utc::now() + chrono::Duration::hours(1));
+
+    let listed_keys = client
+        .list_keys("test_token")
+        .await
+        .json::<Vec<ProtectedApiKey>>();
+    assert_eq!(listed_keys[0].expires_at, Some(expires_at));
+
+    let expires_at = Utc::now() + chrono::Duration::days(1);
+    let response = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                expires_at: Some(expires_at),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.json::<ApiKey>().expires_at, Some(expires_at));
+}
+
+#[tokio::test]
+async fn test_create_key_with_invalid_expiration() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    for key in [
+        InputApiKey {
+            name: "my api key".to_string(),
+            expires_at: Some(Utc::now() + chrono::Duration::days(1)),
+            ttl: Some(3600),
+            ..Default::default()
+        },
+        InputApiKey {
+            name: "my api key".to_string(),
+            expires_at: Some(Utc::now() - chrono::Duration::days(1)),
+            ..Default::default()
+        },
+        InputApiKey {
+            name: "my api key".to_string(),
+            ttl: Some(0),
+            ..Default::default()
+        },
+        InputApiKey {
+            name: "my api key".to_string(),
+            ttl: Some(u64::MAX),
+            ..Default::default()
+        },
+    ] {
+        let response = client.create_key(key, "test_token").await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    let list_response = client.list_keys("test_token").await;
+    assert_eq!(list_response.json::<Vec<ProtectedApiKey>>().len(), 0);
+}
+
+#[tokio::test]
+async fn test_verify_key_requires_service_credential() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+
+    let response = client
+        .verify_key(created_key.secret.clone(), "test_token")
+        .await;
+    assert_eq!(response.status_code(), 401);
+
+    let response = client
+        .server
+        .post("/verify")
+        .json(&LookupSecret {
+            secret: created_key.secret,
+            client_ip: None,
+        })
+        .await;
+    assert_eq!(response.status_code(), 401);
+}
+
+#[tokio::test]
+async fn test_verify_key_disabled_without_service_authenticator() {
+    let server = TestServer::new(router_with_secret_hasher(
+        TestAuthProvider::new(),
+        InMemoryStorage::new(),
+        UuidSecretGenerator::new(),
+        Sha256SecretHasher::new("test_pepper"),
+    ))
+    .unwrap();
+
+    let response = server
+        .post("/verify")
+        .json(&LookupSecret {
+            secret: "secret".to_string(),
+            client_ip: None,
+        })
+        .add_header("Authorization", "Bearer service_token")
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_prefixed_secret_generator() {
+    use prefixed_secret_generator::{PrefixedSecretGenerator, DEFAULT_ALPHABET};
+
+    let generator = PrefixedSecretGenerator::new("aks", 32, DEFAULT_ALPHABET).unwrap();
+    let secret = generator.generate().await;
+
+    let parts = secret.split('_').collect::<Vec<&str>>();
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[0], "aks");
+    assert_eq!(parts[1].len(), 43);
+    assert_eq!(parts[2].len(), 6);
+    assert!(generator.validate_format(&secret));
+    assert_ne!(secret, generator.generate().await);
+
+    let mut tampered_secret = secret.clone().into_bytes();
+    tampered_secret[6] = if tampered_secret[6] == b'a' {
+        b'b'
+    } else {
+        b'a'
+    };
+    assert!(!generator.validate_format(&String::from_utf8(tampered_secret).unwrap()));
+    assert!(!generator.validate_format(&secret[..secret.len() - 1]));
+    assert!(!generator.validate_format(&secret.replacen("aks", "sk", 1)));
+    assert!(!PrefixedSecretGenerator::new("sk", 32, DEFAULT_ALPHABET)
+        .unwrap()
+        .validate_format(&secret));
+
+    let generator = PrefixedSecretGenerator::new("hex_key", 16, "0123456789abcdef").unwrap();
+    let secret = generator.generate().await;
+    assert!(secret.starts_with("hex_key_"));
+    assert!(generator.validate_format(&secret));
+    assert!(!generator.validate_format(&secret.to_uppercase()));
+
+    assert!(PrefixedSecretGenerator::new("", 32, DEFAULT_ALPHABET).is_err());
+    assert!(PrefixedSecretGenerator::new("aks", 0, DEFAULT_ALPHABET).is_err());
+    assert!(PrefixedSecretGenerator::new("aks", 32, "a").is_err());
+    assert!(PrefixedSecretGenerator::new("aks", 32, "aab").is_err());
+    assert!(PrefixedSecretGenerator::new("aks", 32, "ab_").is_err());
+}
+
+#[tokio::test]
+async fn test_previous_secret_generators() {
+    use prefixed_secret_generator::{PrefixedSecretGenerator, DEFAULT_ALPHABET};
+
+    let storage_adapter = InMemoryStorage::new();
+    let server = |secret_generator: Arc<dyn SecretGenerator>,
+                  previous_secret_generators: Vec<Arc<dyn SecretGenerator>>| {
+        let api_key_server = ApiKeyServer::builder()
+            .with_auth_provider(TestAuthProvider::new())
+            .with_storage_adapter(storage_adapter.clone())
+            .with_secret_generator(secret_generator)
+            .with_previous_secret_generators(previous_secret_generators)
+            .with_secret_hasher(Sha256SecretHasher::new("test_pepper"))
+            .build()
+            .unwrap();
+        TestServer::new(api_key_server.router()).unwrap()
+    };
+    let lookup = |server: &TestServer, secret: &str| {
+        server
+            .post("/lookup")
+            .json(&LookupSecret {
+                secret: secret.to_string(),
+                client_ip: None,
+            })
+            .authorization_bearer("test_token")
+    };
+
+    let uuid_key = server(UuidSecretGenerator::new(), Vec::new())
+        .post("/keys")
+        .json(&InputApiKey::default())
+        .authorization_bearer("test_token")
+        .await
+        .json::<ApiKey>();
+
+    let prefixed_secret_generator =
+        PrefixedSecretGenerator::new("aks", 32, DEFAULT_ALPHABET).unwrap();
+    let prefixed_server = server(prefixed_secret_generator.clone(), Vec::new());
+    let prefixed_key = prefixed_server
+        .post("/keys")
+        .json(&InputApiKey::default())
+        .authorization_bearer("test_token")
+        .await
+        .json::<ApiKey>();
+    assert_eq!(
+        lookup(&prefixed_server, &uuid_key.secret)
+            .await
+            .status_code(),
+        404
+    );
+
+    let server = server(
+        prefixed_secret_generator,
+        vec![UuidSecretGenerator::new() as Arc<dyn SecretGenerator>],
+    );
+    let response = lookup(&server, &uuid_key.secret).await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.json::<LookedUpApiKey>().key.id, uuid_key.id);
+    let response = lookup(&server, &prefixed_key.secret).await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.json::<LookedUpApiKey>().key.id, prefixed_key.id);
+    assert_eq!(lookup(&server, "not-a-secret").await.status_code(), 404);
+}
+
+type MakeError = fn() -> StorageError;
+
+/// Storage whose every call fails with the error made by its function, or
+/// panics if that function does.
+struct FailingStorage(MakeError);
+
+#[async_trait]
+impl StorageAdapter for FailingStorage {
+    async fn create_key(&self, _: &Owner, _: ApiKey) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn list_keys(&self, _: &Owner) -> Result<Vec<ApiKey>, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn delete_key(&self, _: &Owner, _: Uuid) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn update_key(&self, _: &Owner, _: ApiKey) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn lookup_key(&self, _: &Owner, _: &str) -> Result<Option<ApiKey>, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn lookup_key_across_users(
+        &self,
+        _: &str,
+    ) -> Result<Option<(Owner, ApiKey)>, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn purge_expired_keys(&self, _: DateTime<Utc>) -> Result<u64, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn record_last_used(&self, _: &[KeyUse]) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn count_keys(&self) -> Result<u64, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn consume_rate_limit(
+        &self,
+        _: Uuid,
+        _: &RateLimit,
+        _: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, StorageError> {
+        Err((self.0)())
+    }
+
+    async fn increment_usage(
+        &self,
+        _: Uuid,
+        _: Option<u64>,
+        _: DateTime<Utc>,
+    ) -> Result<(bool, u64), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn refund_usage(&self, _: Uuid, _: DateTime<Utc>) -> Result<(), StorageError> {
+        Err((self.0)())
+    }
+
+    async fn get_usage(
+        &self,
+        _: Uuid,
+        _: NaiveDate,
+        _: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, StorageError> {
+        Err((self.0)())
+    }
+}
+
+#[tokio::test]
+async fn test_problem_responses() {
+    let client = TestClient::new(InMemoryStorage::new());
+
+    let response = client
+        .server
+        .delete(&format!("/keys/{}", Uuid::new_v4()))
+        .add_header("Authorization", "Bearer test_token")
+        .add_header("x-request-id", "delete-request")
+        .await;
+    assert_eq!(response.status_code(), 404);
+    assert_eq!(response.header(header::CONTENT_TYPE), PROBLEM_JSON);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.problem_type, "about:blank");
+    assert_eq!(error.title, "Not Found");
+    assert_eq!(error.status, 404);
+    assert_eq!(error.code, "key_not_found");
+    assert_eq!(error.request_id.as_deref(), Some("delete-request"));
+
+    let response = client.server.get("/keys").await;
+    assert_eq!(response.status_code(), 401);
+    assert_eq!(response.header(header::CONTENT_TYPE), PROBLEM_JSON);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.code, "unauthorized");
+    assert_eq!(
+        error.request_id.as_deref(),
+        Some(response.header("x-request-id").to_str().unwrap())
+    );
+
+    let response = client.verify_key("secret".to_string(), "test_token").await;
+    assert_eq!(response.status_code(), 401);
+    assert_eq!(
+        response.json::<ApiError>().code,
+        "invalid_service_credential"
+    );
+
+    let response = client
+        .server
+        .post("/keys")
+        .add_header("Authorization", "Bearer test_token")
+        .json(&serde_json::json!({ "scopes": [] }))
+        .await;
+    assert_eq!(response.status_code(), 422);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.code, "unprocessable_entity");
+    assert!(error.detail.contains("name"));
+
+    let response = client
+        .server
+        .put("/keys")
+        .add_header("Authorization", "Bearer test_token")
+        .await;
+    assert_eq!(response.status_code(), 405);
+    assert!(response.maybe_header(header::ALLOW).is_some());
+    assert_eq!(response.json::<ApiError>().code, "method_not_allowed");
+
+    let client = TestClient::new(Arc::new(FailingStorage(|| {
+        StorageError::InternalError("connection to db.internal:5432 refused".to_string())
+    })));
+    let response = client.list_keys("test_token").await;
+    assert_eq!(response.status_code(), 500);
+    let error = response.json::<ApiError>();
+    assert_eq!(error.code, "internal_error");
+    assert_eq!(error.detail, "Failed to list keys");
+    assert!(!response.text().contains("db.internal"));
+}
+
+#[tokio::test]
+async fn test_storage_error_statuses() {
+    let cases: [(MakeError, u16, &str); 5] = [
+        (
+            || StorageError::Conflict("duplicate id".to_string()),
+            409,
+            "conflict",
+        ),
+        (
+            || StorageError::unavailable("connection refused"),
+            503,
+            "storage_unavailable",
+        ),
+        (
+            || StorageError::Timeout("statement timeout".to_string()),
+            504,
+            "storage_timeout",
+        ),
+        (
+            || StorageError::InvalidInput("name is too long".to_string()),
+            400,
+            "invalid_input",
+        ),
+        (
+            || StorageError::InternalError("corrupt row".to_string()),
+            500,
+            "internal_error",
+        ),
+    ];
+
+    for (storage_error, status, code) in cases {
+        let client = TestClient::new(Arc::new(FailingStorage(storage_error)));
+        let response = client
+            .create_key(
+                InputApiKey {
+                    name: "my api key".to_string(),
+                    ..Default::default()
+                },
+                "test_token",
+            )
+            .await;
+        assert_eq!(response.status_code(), status);
+        let error = response.json::<ApiError>();
+        assert_eq!(error.code, code);
+        assert_eq!(
+            response.maybe_header(header::RETRY_AFTER).is_some(),
+            status == 503
+        );
+    }
+
+    let client = TestClient::new(Arc::new(FailingStorage(|| {
+        StorageError::InvalidInput("name is too long".to_string())
+    })));
+    let error = client.list_keys("test_token").await.json::<ApiError>();
+    assert_eq!(error.detail, "Failed to list keys: name is too long");
+
+    let client = TestClient::new(Arc::new(FailingStorage(|| StorageError::Unavailable {
+        reason: "loading".to_string(),
+        retry_after: Some(Duration::from_millis(2500)),
+    })));
+    let response = client
+        .verify_key(Uuid::new_v4().to_string(), "service_token")
+        .await;
+    assert_eq!(response.status_code(), 503);
+    assert_eq!(response.header(header::RETRY_AFTER), "3");
+
+    assert_eq!(
+        StorageError::Timeout("statement timeout".to_string()).to_string(),
+        "timed out: statement timeout"
+    );
+}
+
+#[tokio::test]
+async fn test_malformed_secrets_are_rejected_without_storage() {
+    let client = TestClient::new(Arc::new(FailingStorage(|| unreachable!())));
+
+    let lookup_response = client
+        .lookup_key("invalid_secret".to_string(), "test_token")
+        .await;
+    assert_eq!(lookup_response.status_code(), 404);
+
+    let verify_response = client
+        .verify_key("invalid_secret".to_string(), "service_token")
+        .await;
+    assert_eq!(verify_response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_secrets_are_hashed_at_rest() {
+    let storage_adapter = InMemoryStorage::new();
+    let client = TestClient::new(storage_adapter.clone());
+
+    let created_key = client
+        .create_key(
+            InputApiKey {
+                name: "my api key".to_string(),
+                ..Default::default()
+            },
+            "test_token",
+        )
+        .await
+        .json::<ApiKey>();
+    let regenerated_key = client
+        .regenerate_key(created_key.id, "test_token")
+        .await
+        .json::<ApiKey>();
+
+    let stored_keys = storage_adapter
+        .list_keys(&Owner::user("test_token"))
+        .await
+        .unwrap();
+    assert_eq!(stored_keys.len(), 1);
+    assert_ne!(stored_keys[0].secret, created_key.secret);
+    assert_ne!(stored_keys[0].secret, regenerated_key.secret);
+    assert_eq!(
+        stored_keys[0].secret,
+        Sha256SecretHasher::new("test_pepper")
+            .hash(&regenerated_key.secret)
+            .await
+    );
+
+    let lookup_response = client.lookup_key(created_key.secret, "test_token").await;
+    assert_eq!(lookup_response.status_code(), 404);
+
+    let lookup_response = client
+        .lookup_key(regenerated_key.secret, "test_token")
+        .await;
+    assert_eq!(lookup_response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn test_secret_pepper_is_required() {
+    let error = ApiKeyServer::builder()
+        .with_auth_provider(TestAuthProvider::new())
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_storage_adapter(InMemoryStorage::new())
+        .build()
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("with_secret_pepper"));
+
+    let storage_adapter = InMemoryStorage::new();
+    let server = TestServer::new(router(
+        TestAuthProvider::new(),
+        storage_adapter.clone(),
+        UuidSecretGenerator::new(),
+        "test_pepper",
+    ))
+    .unwrap();
+    let created_key = server
+        .post("/keys")
+        .json(&InputApiKey {
+            name: "my api key".to_string(),
+            ..Default::default()
+        })
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .json::<ApiKey>();
+
+    let stored_keys = storage_adapter
+        .list_keys(&Owner::user("test_token"))
+        .await
+        .unwrap();
+    assert_eq!(
+        stored_keys[0].secret,
+        Sha256SecretHasher::new("test_pepper")
+            .hash(&created_key.secret)
+            .await
+    );
+}
+
+#[tokio::test]
+async fn test_sha256_secret_hasher_depends_on_pepper() {
+    let hasher = Sha256SecretHasher::new("pepper");
+
+    assert_eq!(hasher.hash("secret").await, hasher.hash("secret").await);
+    assert_ne!(hasher.hash("secret").await, hasher.hash("other").await);
+    assert_ne!(
+        hasher.hash("secret").await,
+        Sha256SecretHasher::new("other pepper").hash("secret").await
+    );
+}
+
+#[tokio::test]
+async fn test_argon2id_secret_hasher_depends_on_pepper() {
+    let params = argon2::Params::new(256, 1, 1, None).unwrap();
+    let hasher =
+        argon2_secret_hasher::Argon2idSecretHasher::with_params("pepper", params.clone()).unwrap();
+    let other_hasher =
+        argon2_secret_hasher::Argon2idSecretHasher::with_params("other pepper", params).unwrap();
+
+    assert_eq!(hasher.hash("secret").await, hasher.hash("secret").await);
+    assert_ne!(hasher.hash("secret").await, hasher.hash("other").await);
+    assert_ne!(
+        hasher.hash("secret").await,
+        other_hasher.hash("secret").await
+    );
+}
+
+#[tokio::test]
+async fn test_lookup_hashes_secret_once() {
+    #[derive(Default)]
+    struct CountingHasher(std::sync::atomic::AtomicUsize);
+
+    #[async_trait]
+    impl SecretHasher for CountingHasher {
+        async fn hash(&self, secret: &str) -> String {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            secret.to_string()
+        }
+    }
+
+    let hasher = Arc::new(CountingHasher::default());
+    let api_key_server = ApiKeyServer::builder()
+        .with_storage_adapter(InMemoryStorage::new())
+        .with_auth_provider(TestAuthProvider::new())
+        .with_secret_generator(UuidSecretGenerator::new())
+        .with_secret_hasher(hasher.clone())
+        .with_service_authenticator(StaticTokenAuthenticator::new("service_token"))
+        .build()
+        .unwrap();
+    let server = TestServer::new(api_key_server.router()).unwrap();
+    let secret = server
+        .post("/keys")
+        .json(&serde_json::json!({ "name": "my api key" }))
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .json::<ApiKey>()
+        .secret;
+
+    hasher.0.store(0, Ordering::SeqCst);
+    server
+        .post("/lookup")
+        .json(&serde_json::json!({ "secret": secret }))
+        .add_header("Authorization", "Bearer test_token")
+        .await
+        .assert_status_ok();
+    assert_eq!(hasher.0.load(Ordering::SeqCst), 1);
+    server
+        .post("/verify")
+        .json(&serde_json::json!({ "secret": secret }))
+        .add_header("Authorization", "Bearer service_token")
+        .await
+        .assert_status_ok();
+    assert_eq!(hasher.0.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_storage_persists_across_reopen() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("api_keys.db");
+    let key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "my api key".to_string(),
+        secret: "my secret".to_string(),
+        expires_at: None,
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: None,
+        monthly_quota: None,
+    };
+
+    sqlite_storage::SqliteStorage::open(&path)
+        .unwrap()
+        .create_key(&Owner::user("test_token"), key.clone())
+        .await
+        .unwrap();
+
+    let storage = sqlite_storage::SqliteStorage::open(&path).unwrap();
+    let keys = storage.list_keys(&Owner::user("test_token")).await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].id, key.id);
+
+    let looked_up_key = storage
+        .lookup_key(&Owner::user("test_token"), "my secret")
+        .await
+        .unwrap();
+    assert_eq!(looked_up_key.map(|key| key.id), Some(key.id));
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_storage_reports_conflicts() {
+    let storage = sqlite_storage::SqliteStorage::open_in_memory().unwrap();
+    let key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "my api key".to_string(),
+        secret: "my secret".to_string(),
+        expires_at: None,
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: None,
+        monthly_quota: None,
+    };
+
+    storage
+        .create_key(&Owner::user("test_token"), key.clone())
+        .await
+        .unwrap();
+    let result = storage.create_key(&Owner::user("test_token"), key).await;
+    assert!(matches!(result, Err(StorageError::Conflict(_))));
+}
+
+#[cfg(feature = "redis")]
+#[tokio::test]
+#[ignore = "requires a local Redis instance, see REDIS_URL"]
+async fn test_redis_storage_reports_secret_conflicts() {
+    let storage = redis_test_storage().await;
+    let key = ApiKey {
+        id: Uuid::new_v4(),
+        name: "my api key".to_string(),
+        secret: "my secret".to_string(),
+        expires_at: None,
+        scopes: Vec::new(),
+        last_used_at: None,
+        last_used_ip: None,
+        rate_limit: None,
+        monthly_quota: None,
+    };
+    let other_key = ApiKey {
+        id: Uuid::new_v4(),
+        secret: "other secret".to_string(),
+        ..key.clone()
+    };
+    let owner = Owner::user("test_token");
+
+    storage.create_key(&owner, key.clone()).await.unwrap();
+    storage.create_key(&owner, other_key.clone()).await.unwrap();
+    let result = storage
+        .create_key(
+            &Owner::user("other_token"),
+            ApiKey {
+                id: Uuid::new_v4(),
+                ..key.clone()
+            },
+        )
+        .await;
+    assert!(matches!(result, Err(StorageError::Conflict(_))));
+    let result = storage
+        .update_key(
+            &owner,
+            ApiKey {
+                secret: key.secret.clone(),
+                ..other_key
+            },
+        )
+        .await;
+    assert!(matches!(result, Err(StorageError::Conflict(_))));
+
+    let found = storage.lookup_key_across_users(&key.secret).await.unwrap();
+    assert_eq!(found.map(|(_, found)| found.id), Some(key.id));
+    assert_eq!(storage.list_keys(&owner).await.unwrap().len(), 2);
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_postgres_storage_reports_unavailable() {
+    let result =
+        postgres_storage::PostgresStorage::connect("postgres://postgres@127.0.0.1:1/postgres", 1)
+            .await;
+    assert!(matches!(result, Err(StorageError::Unavailable { .. })));
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+#[ignore = "requires a local Postgres instance, see POSTGRES_URL"]
+async fn test_postgres_storage_shutdown() {
+    let storage_adapter = postgres_test_storage().await;
+    storage_adapter.health_check().await.unwrap();
+    assert_eq!(storage_adapter.count_keys().await.unwrap(), 0);
+
+    storage_adapter.shutdown().await.unwrap();
+    assert!(matches!(
+        storage_adapter.count_keys().await,
+        Err(StorageError::Unavailable { .. })
+    ));
+    assert!(matches!(
+        storage_adapter.health_check().await,
+        Err(StorageError::Unavailable { .. })
+    ));
+}
@@ -0,0 +1,122 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+/// Serves HTTPS with a certificate chain and private key read from PEM
+/// files. When a file of client certificate authorities is given, clients
+/// must present a certificate signed by one of them.
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    contents: Mutex<Vec<Vec<u8>>>,
+    rustls_config: RustlsConfig,
+}
+
+impl TlsConfig {
+    pub fn new(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+        client_ca_path: Option<PathBuf>,
+    ) -> Result<Arc<Self>, String> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let contents = read_files(&cert_path, &key_path, client_ca_path.as_deref())?;
+        let rustls_config = RustlsConfig::from_config(Arc::new(server_config(&contents)?));
+        Ok(Arc::new(Self {
+            cert_path,
+            key_path,
+            client_ca_path,
+            contents: Mutex::new(contents),
+            rustls_config,
+        }))
+    }
+
+    /// Reads the files again and, if any has changed, serves new
+    /// connections with them. Returns whether they had changed. New
+    /// connections keep being served with the previous files when the
+    /// changed ones are invalid.
+    pub fn reload(&self) -> Result<bool, String> {
+        let contents = read_files(
+            &self.cert_path,
+            &self.key_path,
+            self.client_ca_path.as_deref(),
+        )?;
+        let mut loaded = self.contents.lock().unwrap();
+        if *loaded == contents {
+            return Ok(false);
+        }
+
+        self.rustls_config
+            .reload_from_config(Arc::new(server_config(&contents)?));
+        *loaded = contents;
+        Ok(true)
+    }
+
+    pub(crate) fn rustls_config(&self) -> RustlsConfig {
+        self.rustls_config.clone()
+    }
+}
+
+fn read_files(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> Result<Vec<Vec<u8>>, String> {
+    [Some(cert_path), Some(key_path), client_ca_path]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+/// Configures rustls from the contents of the certificate chain, private key
+/// and, if any, client certificate authorities files, in that order.
+fn server_config(contents: &[Vec<u8>]) -> Result<ServerConfig, String> {
+    let certs = CertificateDer::pem_slice_iter(&contents[0])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid TLS certificate: {}", e))?;
+    if certs.is_empty() {
+        return Err("No certificate found in the TLS certificate file".to_string());
+    }
+    let key = PrivateKeyDer::from_pem_slice(&contents[1])
+        .map_err(|e| format!("Invalid TLS private key: {}", e))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match contents.get(2) {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(client_ca) {
+                let cert = cert.map_err(|e| format!("Invalid TLS client CA: {}", e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid TLS client CA: {}", e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .build()
+                .map_err(|e| format!("Invalid TLS client CA: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or private key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Response},
+};
+use opentelemetry::{
+    propagation::{Extractor, Injector, TextMapPropagator},
+    trace::{TraceContextExt, TraceId},
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing::{field, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Opens the span of `request`, as a child of the span in its `traceparent`
+/// header if any. Only the method, route and ids of the request are
+/// recorded, never its other headers or body, which may carry secrets.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let method = request.method();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format_args!("{} {}", method, route),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        http.request.method = %method,
+        http.route = route,
+        http.response.status_code = field::Empty,
+        request_id,
+        trace_id = field::Empty,
+    );
+
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let parent_trace_id = parent.span().span_context().trace_id();
+    span.set_parent(parent);
+    // Without an OpenTelemetry layer the span has no trace of its own,
+    // but logs can still be correlated with the caller's.
+    let trace_id = match span.context().span().span_context().trace_id() {
+        TraceId::INVALID => parent_trace_id,
+        trace_id => trace_id,
+    };
+    if trace_id != TraceId::INVALID {
+        span.record("trace_id", field::display(trace_id));
+    }
+    span
+}
+
+pub fn record_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    tracing::info!(
+        latency_ms = latency.as_millis() as u64,
+        status = response.status().as_u16(),
+        "Finished processing request"
+    );
+}
+
+/// Adds the `traceparent` header of the current span to `headers`, if the
+/// span belongs to a trace.
+pub fn inject(headers: &mut HeaderMap) {
+    TraceContextPropagator::new()
+        .inject_context(&Span::current().context(), &mut HeaderInjector(headers));
+}
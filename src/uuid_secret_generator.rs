@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use uuid::Uuid;
+
+use crate::SecretGenerator;
+
+pub struct UuidSecretGenerator;
+
+impl UuidSecretGenerator {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait]
+impl SecretGenerator for UuidSecretGenerator {
+    async fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    fn validate_format(&self, secret: &str) -> bool {
+        Uuid::try_parse(secret).is_ok()
+    }
+}
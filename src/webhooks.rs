@@ -0,0 +1,523 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{Mutex, Notify},
+    task::AbortHandle,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::{trace_context, Owner, ProtectedApiKey};
+
+pub const ID_HEADER: &str = "webhook-id";
+pub const TIMESTAMP_HEADER: &str = "webhook-timestamp";
+pub const SIGNATURE_HEADER: &str = "webhook-signature";
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum WebhookEventType {
+    #[serde(rename = "key.created")]
+    KeyCreated,
+    #[serde(rename = "key.regenerated")]
+    KeyRegenerated,
+    #[serde(rename = "key.deleted")]
+    KeyDeleted,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct WebhookEvent {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub owner: Owner,
+    pub key_id: Uuid,
+    /// The key as it is after the event, or `None` once it has been deleted.
+    pub key: Option<ProtectedApiKey>,
+}
+
+impl WebhookEvent {
+    pub fn new(
+        event_type: WebhookEventType,
+        owner: Owner,
+        key_id: Uuid,
+        key: Option<ProtectedApiKey>,
+    ) -> Self {
+        WebhookEvent {
+            id: Uuid::new_v4(),
+            event_type,
+            timestamp: Utc::now(),
+            owner,
+            key_id,
+            key,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookSubscription {
+    pub url: String,
+    /// Key used to sign deliveries, shared with the receiver.
+    pub secret: String,
+}
+
+/// How deliveries are retried. The delay doubles after every failed
+/// attempt, up to `max_backoff`.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A delivery that failed every attempt, as appended to the dead-letter
+/// queue.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DeadLetter {
+    pub url: String,
+    pub event: WebhookEvent,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Signs a delivery as `sha256=<hex>`, the HMAC-SHA256 of
+/// `<id>.<timestamp>.<body>` keyed with the subscription secret.
+pub fn sign(secret: &str, id: Uuid, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(format!("{}.{}.{}", id, timestamp, body).as_bytes());
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256={}", signature)
+}
+
+/// A delivery that has not succeeded or been dead-lettered yet, as recorded
+/// in the outbox.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct PendingDelivery {
+    url: String,
+    event: WebhookEvent,
+}
+
+/// Line of the outbox, which records every delivery before its first attempt
+/// and once it is settled.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+// Entries only live while being read or written.
+#[allow(clippy::large_enum_variant)]
+enum OutboxEntry {
+    Pending {
+        delivery_id: Uuid,
+        #[serde(flatten)]
+        delivery: PendingDelivery,
+    },
+    Settled {
+        delivery_id: Uuid,
+    },
+}
+
+/// A pending delivery that is being attempted.
+struct InFlight {
+    delivery: PendingDelivery,
+    attempts: u32,
+    error: Option<String>,
+    task: Option<AbortHandle>,
+}
+
+#[derive(Default)]
+struct Outbox {
+    file: Option<File>,
+    in_flight: HashMap<Uuid, InFlight>,
+}
+
+/// POSTs key events to every subscription in the background. Deliveries
+/// that still fail after the last retry are appended to a JSON-lines
+/// dead-letter file.
+///
+/// Deliveries are recorded in a JSON-lines outbox before they are first
+/// attempted, so those cut short by a crash are retried by
+/// [`WebhookDispatcher::resume`], and those still being retried at shutdown
+/// are dead-lettered by [`WebhookDispatcher::shutdown`].
+pub struct WebhookDispatcher {
+    client: reqwest::Client,
+    subscriptions: Vec<WebhookSubscription>,
+    retry_policy: RetryPolicy,
+    dead_letter_path: PathBuf,
+    dead_letter_lock: Mutex<()>,
+    outbox_path: PathBuf,
+    outbox: Mutex<Outbox>,
+    settled: Notify,
+}
+
+impl WebhookDispatcher {
+    pub fn new(
+        subscriptions: Vec<WebhookSubscription>,
+        retry_policy: RetryPolicy,
+        dead_letter_path: impl AsRef<Path>,
+        outbox_path: impl AsRef<Path>,
+    ) -> Arc<Self> {
+        Arc::new(WebhookDispatcher {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("HTTP client configuration is valid"),
+            subscriptions,
+            retry_policy,
+            dead_letter_path: dead_letter_path.as_ref().to_path_buf(),
+            dead_letter_lock: Mutex::new(()),
+            outbox_path: outbox_path.as_ref().to_path_buf(),
+            outbox: Mutex::new(Outbox::default()),
+            settled: Notify::new(),
+        })
+    }
+
+    /// Records a delivery of `event` to every subscription in the outbox and
+    /// starts them. Returns once the deliveries are on disk, or logs why they
+    /// could not be recorded and attempts them anyway.
+    pub async fn dispatch(self: &Arc<Self>, event: WebhookEvent) {
+        let mut outbox = self.outbox.lock().await;
+        let mut lines = Vec::new();
+        let mut deliveries = Vec::new();
+        for subscription in &self.subscriptions {
+            let delivery_id = Uuid::new_v4();
+            let delivery = PendingDelivery {
+                url: subscription.url.clone(),
+                event: event.clone(),
+            };
+            match outbox_line(&OutboxEntry::Pending {
+                delivery_id,
+                delivery: delivery.clone(),
+            }) {
+                Ok(line) => lines.extend(line),
+                Err(e) => {
+                    tracing::error!("Failed to serialize webhook event: {:?}", e);
+                    return;
+                }
+            }
+            deliveries.push((delivery_id, delivery));
+        }
+
+        if let Err(e) = self.append(&mut outbox, &lines, true).await {
+            tracing::error!("Failed to record webhook deliveries: {:?}", e);
+        }
+        for (delivery_id, delivery) in deliveries {
+            self.start(&mut outbox, delivery_id, delivery);
+        }
+    }
+
+    /// Restarts the deliveries that the outbox still has pending, which were
+    /// cut short when the server last stopped, with a fresh retry schedule.
+    /// Deliveries to URLs that are no longer subscribed are dead-lettered.
+    /// Returns how many deliveries were restarted.
+    pub async fn resume(self: &Arc<Self>) -> std::io::Result<usize> {
+        let mut outbox = self.outbox.lock().await;
+        let mut pending = Vec::<(Uuid, PendingDelivery)>::new();
+        match tokio::fs::read_to_string(&self.outbox_path).await {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    // The last line may have been cut short by a crash.
+                    match serde_json::from_str::<OutboxEntry>(line) {
+                        Ok(OutboxEntry::Pending {
+                            delivery_id,
+                            delivery,
+                        }) => pending.push((delivery_id, delivery)),
+                        Ok(OutboxEntry::Settled { delivery_id }) => {
+                            pending.retain(|(id, _)| *id != delivery_id)
+                        }
+                        Err(e) => tracing::warn!("Skipping malformed webhook outbox line: {}", e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut resumed = Vec::new();
+        for (delivery_id, delivery) in pending {
+            if self
+                .subscriptions
+                .iter()
+                .any(|subscription| subscription.url == delivery.url)
+            {
+                resumed.push((delivery_id, delivery));
+            } else {
+                self.dead_letter(&DeadLetter {
+                    url: delivery.url,
+                    event: delivery.event,
+                    attempts: 0,
+                    error: "No longer subscribed".to_string(),
+                    failed_at: Utc::now(),
+                })
+                .await?;
+            }
+        }
+
+        // Only the deliveries that are still pending are kept.
+        outbox.file = None;
+        let mut file = File::create(&self.outbox_path).await?;
+        for (delivery_id, delivery) in &resumed {
+            file.write_all(&outbox_line(&OutboxEntry::Pending {
+                delivery_id: *delivery_id,
+                delivery: delivery.clone(),
+            })?)
+            .await?;
+        }
+        file.sync_data().await?;
+        drop(file);
+
+        let count = resumed.len();
+        for (delivery_id, delivery) in resumed {
+            self.start(&mut outbox, delivery_id, delivery);
+        }
+        Ok(count)
+    }
+
+    /// Waits up to `timeout` for the deliveries in flight to settle, then
+    /// dead-letters those that are still being retried.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let settled = self.settled.notified();
+                if self.outbox.lock().await.in_flight.is_empty() {
+                    return;
+                }
+                settled.await;
+            }
+        })
+        .await;
+
+        let mut outbox = self.outbox.lock().await;
+        let in_flight = outbox.in_flight.drain().collect::<Vec<_>>();
+        if !in_flight.is_empty() {
+            tracing::warn!(
+                "Dead-lettering {} webhook deliveries still in flight",
+                in_flight.len()
+            );
+        }
+        for (delivery_id, in_flight) in in_flight {
+            if let Some(task) = in_flight.task {
+                task.abort();
+            }
+            let dead_letter = DeadLetter {
+                url: in_flight.delivery.url,
+                event: in_flight.delivery.event,
+                attempts: in_flight.attempts,
+                error: match in_flight.error {
+                    Some(error) => format!("Server shut down while retrying: {}", error),
+                    None => "Server shut down before the delivery was attempted".to_string(),
+                },
+                failed_at: Utc::now(),
+            };
+            if let Err(e) = self.dead_letter(&dead_letter).await {
+                tracing::error!("Failed to write webhook dead letter: {:?}", e);
+                continue;
+            }
+            self.settle(&mut outbox, delivery_id).await;
+        }
+    }
+
+    fn start(self: &Arc<Self>, outbox: &mut Outbox, delivery_id: Uuid, delivery: PendingDelivery) {
+        let Some(subscription) = self
+            .subscriptions
+            .iter()
+            .find(|subscription| subscription.url == delivery.url)
+        else {
+            return;
+        };
+        let body = match serde_json::to_string(&delivery.event) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Failed to serialize webhook event: {:?}", e);
+                return;
+            }
+        };
+
+        // The subscription URL is left out of the span, as it may carry
+        // credentials of the receiver.
+        let span = tracing::info_span!(
+            "webhook_delivery",
+            event.id = %delivery.event.id,
+            event.type = ?delivery.event.event_type,
+        );
+        let task = tokio::spawn(
+            self.clone()
+                .deliver(
+                    delivery_id,
+                    subscription.clone(),
+                    delivery.event.clone(),
+                    body,
+                )
+                .instrument(span),
+        );
+        outbox.in_flight.insert(
+            delivery_id,
+            InFlight {
+                delivery,
+                attempts: 0,
+                error: None,
+                task: Some(task.abort_handle()),
+            },
+        );
+    }
+
+    async fn deliver(
+        self: Arc<Self>,
+        delivery_id: Uuid,
+        subscription: WebhookSubscription,
+        event: WebhookEvent,
+        body: String,
+    ) {
+        let mut backoff = self.retry_policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match self.send(&subscription, event.id, &body).await {
+                Ok(()) => break,
+                Err(error) => error,
+            };
+
+            if attempts >= self.retry_policy.max_attempts {
+                let dead_letter = DeadLetter {
+                    url: subscription.url,
+                    event,
+                    attempts,
+                    error,
+                    failed_at: Utc::now(),
+                };
+                if let Err(e) = self.dead_letter(&dead_letter).await {
+                    tracing::error!("Failed to write webhook dead letter: {:?}", e);
+                    // Left pending, so the delivery is retried on restart.
+                    return;
+                }
+                break;
+            }
+
+            if let Some(in_flight) = self.outbox.lock().await.in_flight.get_mut(&delivery_id) {
+                in_flight.attempts = attempts;
+                in_flight.error = Some(error);
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry_policy.max_backoff);
+        }
+
+        let mut outbox = self.outbox.lock().await;
+        self.settle(&mut outbox, delivery_id).await;
+    }
+
+    /// Records that a delivery succeeded or was dead-lettered. The outbox is
+    /// emptied whenever no delivery is left in flight, so it does not grow for
+    /// as long as the server runs.
+    async fn settle(&self, outbox: &mut Outbox, delivery_id: Uuid) {
+        outbox.in_flight.remove(&delivery_id);
+        let result = if outbox.in_flight.is_empty() {
+            outbox.file = None;
+            File::create(&self.outbox_path).await.map(|_| ())
+        } else {
+            match outbox_line(&OutboxEntry::Settled { delivery_id }) {
+                // A settled delivery that is lost in a crash is only attempted
+                // again, so it is not synced.
+                Ok(line) => self.append(outbox, &line, false).await,
+                Err(e) => Err(e.into()),
+            }
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to record settled webhook delivery: {:?}", e);
+        }
+        self.settled.notify_waiters();
+    }
+
+    async fn append(&self, outbox: &mut Outbox, lines: &[u8], sync: bool) -> std::io::Result<()> {
+        if outbox.file.is_none() {
+            outbox.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.outbox_path)
+                    .await?,
+            );
+        }
+        let file = outbox.file.as_mut().expect("outbox file was opened");
+        file.write_all(lines).await?;
+        if sync {
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        subscription: &WebhookSubscription,
+        id: Uuid,
+        body: &str,
+    ) -> Result<(), String> {
+        let timestamp = Utc::now().timestamp();
+        let mut headers = reqwest::header::HeaderMap::new();
+        trace_context::inject(&mut headers);
+        let response = self
+            .client
+            .post(&subscription.url)
+            .headers(headers)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(ID_HEADER, id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                SIGNATURE_HEADER,
+                sign(&subscription.secret, id, timestamp, body),
+            )
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Receiver responded with {}", response.status()))
+        }
+    }
+
+    async fn dead_letter(&self, dead_letter: &DeadLetter) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(dead_letter)?;
+        line.push(b'\n');
+
+        let _lock = self.dead_letter_lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.dead_letter_path)
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_data().await
+    }
+}
+
+fn outbox_line(entry: &OutboxEntry) -> serde_json::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}